@@ -0,0 +1,421 @@
+// Opcode builders for hand-writing small programs, named after the Cowgod mnemonics
+// disasm prints. Registers and nibbles are masked to 4 bits and addresses to 12, so
+// an out-of-range argument can't spill into the neighbouring fields. assemble does the
+// same from source text.
+
+use std::collections::HashMap;
+use std::fmt;
+
+fn nnn(op: u16, addr: u16) -> u16 {
+    op | (addr & 0x0FFF)
+}
+
+fn xnn(op: u16, x: u8, nn: u8) -> u16 {
+    op | ((x as u16 & 0xF) << 8) | nn as u16
+}
+
+fn xyn(op: u16, x: u8, y: u8, n: u8) -> u16 {
+    op | ((x as u16 & 0xF) << 8) | ((y as u16 & 0xF) << 4) | (n as u16 & 0xF)
+}
+
+pub fn cls() -> u16 {
+    0x00E0
+}
+
+pub fn ret() -> u16 {
+    0x00EE
+}
+
+pub fn jp(addr: u16) -> u16 {
+    nnn(0x1000, addr)
+}
+
+pub fn call(addr: u16) -> u16 {
+    nnn(0x2000, addr)
+}
+
+pub fn se_vx_byte(x: u8, byte: u8) -> u16 {
+    xnn(0x3000, x, byte)
+}
+
+pub fn sne_vx_byte(x: u8, byte: u8) -> u16 {
+    xnn(0x4000, x, byte)
+}
+
+pub fn se_vx_vy(x: u8, y: u8) -> u16 {
+    xyn(0x5000, x, y, 0)
+}
+
+pub fn ld_vx_byte(x: u8, byte: u8) -> u16 {
+    xnn(0x6000, x, byte)
+}
+
+pub fn add_vx_byte(x: u8, byte: u8) -> u16 {
+    xnn(0x7000, x, byte)
+}
+
+pub fn ld_vx_vy(x: u8, y: u8) -> u16 {
+    xyn(0x8000, x, y, 0x0)
+}
+
+pub fn or(x: u8, y: u8) -> u16 {
+    xyn(0x8000, x, y, 0x1)
+}
+
+pub fn and(x: u8, y: u8) -> u16 {
+    xyn(0x8000, x, y, 0x2)
+}
+
+pub fn xor(x: u8, y: u8) -> u16 {
+    xyn(0x8000, x, y, 0x3)
+}
+
+pub fn add_vx_vy(x: u8, y: u8) -> u16 {
+    xyn(0x8000, x, y, 0x4)
+}
+
+pub fn sub(x: u8, y: u8) -> u16 {
+    xyn(0x8000, x, y, 0x5)
+}
+
+pub fn shr(x: u8, y: u8) -> u16 {
+    xyn(0x8000, x, y, 0x6)
+}
+
+pub fn subn(x: u8, y: u8) -> u16 {
+    xyn(0x8000, x, y, 0x7)
+}
+
+pub fn shl(x: u8, y: u8) -> u16 {
+    xyn(0x8000, x, y, 0xE)
+}
+
+pub fn sne_vx_vy(x: u8, y: u8) -> u16 {
+    xyn(0x9000, x, y, 0)
+}
+
+pub fn ld_i(addr: u16) -> u16 {
+    nnn(0xA000, addr)
+}
+
+pub fn jp_v0(addr: u16) -> u16 {
+    nnn(0xB000, addr)
+}
+
+pub fn rnd(x: u8, byte: u8) -> u16 {
+    xnn(0xC000, x, byte)
+}
+
+pub fn drw(x: u8, y: u8, n: u8) -> u16 {
+    xyn(0xD000, x, y, n)
+}
+
+pub fn skp(x: u8) -> u16 {
+    xnn(0xE000, x, 0x9E)
+}
+
+pub fn sknp(x: u8) -> u16 {
+    xnn(0xE000, x, 0xA1)
+}
+
+pub fn ld_vx_dt(x: u8) -> u16 {
+    xnn(0xF000, x, 0x07)
+}
+
+pub fn ld_vx_k(x: u8) -> u16 {
+    xnn(0xF000, x, 0x0A)
+}
+
+pub fn ld_dt_vx(x: u8) -> u16 {
+    xnn(0xF000, x, 0x15)
+}
+
+pub fn ld_st_vx(x: u8) -> u16 {
+    xnn(0xF000, x, 0x18)
+}
+
+pub fn add_i_vx(x: u8) -> u16 {
+    xnn(0xF000, x, 0x1E)
+}
+
+pub fn ld_f_vx(x: u8) -> u16 {
+    xnn(0xF000, x, 0x29)
+}
+
+pub fn ld_b_vx(x: u8) -> u16 {
+    xnn(0xF000, x, 0x33)
+}
+
+// FX55, stores V0..=VX at I
+pub fn ld_i_vx(x: u8) -> u16 {
+    xnn(0xF000, x, 0x55)
+}
+
+// FX65, loads V0..=VX from I
+pub fn ld_vx_i(x: u8) -> u16 {
+    xnn(0xF000, x, 0x65)
+}
+
+// Flattens opcodes into ROM bytes, big-endian like the interpreter fetches them
+pub fn to_bytes(program: &[u16]) -> Vec<u8> {
+    program.iter().flat_map(|op| op.to_be_bytes()).collect()
+}
+
+// A source line assemble couldn't turn into bytes, `line` counting from 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    V(u8),
+    Named(&'static str),
+    Value(u16),
+    Label(String),
+}
+
+// Keyword operands, [I] being FX55/FX65's memory at I
+const NAMED: [&str; 9] = ["I", "DT", "ST", "K", "F", "HF", "B", "R", "[I]"];
+
+struct Line {
+    number: usize,
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+// Assembles source in the syntax disassemble prints into a ROM for 0x200. One
+// instruction per line, `;` starts a comment and `name:` defines a label usable anywhere
+// an address is. Numbers are decimal or 0x hex. `LD I, NNNN` with an address above
+// 0xFFF becomes XO-CHIP's F000 NNNN, and `DB` emits raw bytes, e.g. `DB 0xF0, 0x90`.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    let mut labels = HashMap::new();
+    let mut addr = 0x200usize;
+    for (index, text) in source.lines().enumerate() {
+        let number = index + 1;
+        let error = |reason: String| AsmError { line: number, reason };
+        let mut text = text.split(';').next().unwrap().trim();
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || label.contains(char::is_whitespace) || parse_operand(label) != Operand::Label(label.to_string()) {
+                return Err(error(format!("{:?} isn't a valid label", label)));
+            }
+            if labels.insert(label.to_string(), addr as u16).is_some() {
+                return Err(error(format!("label {} is defined twice", label)));
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operands: Vec<Operand> = match operands.trim() {
+            "" => Vec::new(),
+            operands => operands.split(',').map(|operand| parse_operand(operand.trim())).collect(),
+        };
+        let line = Line { number, mnemonic: mnemonic.to_ascii_uppercase(), operands };
+        addr += size(&line);
+        if addr > 0x10000 {
+            return Err(error("program runs past the end of memory".to_string()));
+        }
+        lines.push(line);
+    }
+
+    let mut rom = Vec::with_capacity(addr - 0x200);
+    for line in &lines {
+        encode(line, &labels, &mut rom).map_err(|reason| AsmError { line: line.number, reason })?;
+    }
+    Ok(rom)
+}
+
+fn parse_operand(text: &str) -> Operand {
+    let upper = text.to_ascii_uppercase();
+    if let Some(&named) = NAMED.iter().find(|&&named| named == upper) {
+        return Operand::Named(named);
+    }
+    if let Some(reg) = upper.strip_prefix('V').filter(|reg| reg.len() == 1) {
+        if let Ok(reg) = u8::from_str_radix(reg, 16) {
+            return Operand::V(reg);
+        }
+    }
+    let value = match upper.strip_prefix("0X") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => upper.parse(),
+    };
+    match value {
+        Ok(value) => Operand::Value(value),
+        Err(_) => Operand::Label(text.to_string()),
+    }
+}
+
+// Bytes a line assembles to, worked out before labels are known. Labels always take
+// the short forms.
+fn size(line: &Line) -> usize {
+    match (line.mnemonic.as_str(), line.operands.as_slice()) {
+        ("DB", operands) => operands.len(),
+        ("LD", [Operand::Named("I"), Operand::Value(addr)]) if *addr > 0xFFF => 4,
+        _ => 2,
+    }
+}
+
+fn encode(line: &Line, labels: &HashMap<String, u16>, rom: &mut Vec<u8>) -> Result<(), String> {
+    use Operand::{Named, V};
+
+    let value = |operand: &Operand, max: u16| -> Result<u16, String> {
+        let value = match operand {
+            Operand::Value(value) => *value,
+            Operand::Label(label) => *labels.get(label).ok_or(format!("unknown label {}", label))?,
+            other => return Err(format!("expected a number, found {:?}", other)),
+        };
+        if value > max {
+            return Err(format!("{:#X} is bigger than the {:#X} that fits", value, max));
+        }
+        Ok(value)
+    };
+    let addr = |operand| value(operand, 0xFFF);
+    let byte = |operand| Ok::<u8, String>(value(operand, 0xFF)? as u8);
+    let nibble = |operand| Ok::<u8, String>(value(operand, 0xF)? as u8);
+
+    let opcode = match (line.mnemonic.as_str(), line.operands.as_slice()) {
+        ("DB", operands) => {
+            for operand in operands {
+                rom.push(byte(operand)?);
+            }
+            return Ok(());
+        }
+        ("NOP", []) => 0x0000,
+        ("SCD", [n]) => 0x00C0 | nibble(n)? as u16,
+        ("SCU", [n]) => 0x00D0 | nibble(n)? as u16,
+        ("CLS", []) => cls(),
+        ("RET", []) => ret(),
+        ("SCR", []) => 0x00FB,
+        ("SCL", []) => 0x00FC,
+        ("EXIT", []) => 0x00FD,
+        ("LOW", []) => 0x00FE,
+        ("HIGH", []) => 0x00FF,
+        ("JP", [V(0), target]) => jp_v0(addr(target)?),
+        ("JP", [target]) => jp(addr(target)?),
+        ("CALL", [target]) => call(addr(target)?),
+        ("SE", [V(x), V(y)]) => se_vx_vy(*x, *y),
+        ("SE", [V(x), nn]) => se_vx_byte(*x, byte(nn)?),
+        ("SNE", [V(x), V(y)]) => sne_vx_vy(*x, *y),
+        ("SNE", [V(x), nn]) => sne_vx_byte(*x, byte(nn)?),
+        ("LD", [V(x), V(y)]) => ld_vx_vy(*x, *y),
+        ("LD", [V(x), Named("DT")]) => ld_vx_dt(*x),
+        ("LD", [V(x), Named("K")]) => ld_vx_k(*x),
+        ("LD", [V(x), Named("[I]")]) => ld_vx_i(*x),
+        ("LD", [V(x), Named("R")]) => 0xF085 | (*x as u16) << 8,
+        ("LD", [V(x), nn]) => ld_vx_byte(*x, byte(nn)?),
+        ("LD", [Named("I"), target]) => match value(target, 0xFFFF)? {
+            long if size(line) == 4 => {
+                rom.extend_from_slice(&0xF000u16.to_be_bytes());
+                long
+            }
+            _ => ld_i(addr(target)?),
+        },
+        ("LD", [Named("DT"), V(x)]) => ld_dt_vx(*x),
+        ("LD", [Named("ST"), V(x)]) => ld_st_vx(*x),
+        ("LD", [Named("F"), V(x)]) => ld_f_vx(*x),
+        ("LD", [Named("HF"), V(x)]) => 0xF030 | (*x as u16) << 8,
+        ("LD", [Named("B"), V(x)]) => ld_b_vx(*x),
+        ("LD", [Named("[I]"), V(x)]) => ld_i_vx(*x),
+        ("LD", [Named("R"), V(x)]) => 0xF075 | (*x as u16) << 8,
+        ("ADD", [V(x), V(y)]) => add_vx_vy(*x, *y),
+        ("ADD", [V(x), nn]) => add_vx_byte(*x, byte(nn)?),
+        ("ADD", [Named("I"), V(x)]) => add_i_vx(*x),
+        ("OR", [V(x), V(y)]) => or(*x, *y),
+        ("AND", [V(x), V(y)]) => and(*x, *y),
+        ("XOR", [V(x), V(y)]) => xor(*x, *y),
+        ("SUB", [V(x), V(y)]) => sub(*x, *y),
+        ("SUBN", [V(x), V(y)]) => subn(*x, *y),
+        // The one-register forms shift VX in place whichever way the shift quirk is set
+        ("SHR", [V(x)]) => shr(*x, *x),
+        ("SHR", [V(x), V(y)]) => shr(*x, *y),
+        ("SHL", [V(x)]) => shl(*x, *x),
+        ("SHL", [V(x), V(y)]) => shl(*x, *y),
+        ("RND", [V(x), nn]) => rnd(*x, byte(nn)?),
+        ("DRW", [V(x), V(y), n]) => drw(*x, *y, nibble(n)?),
+        ("SKP", [V(x)]) => skp(*x),
+        ("SKNP", [V(x)]) => sknp(*x),
+        ("PLANE", [n]) => 0xF001 | (nibble(n)? as u16) << 8,
+        ("AUDIO", []) => 0xF002,
+        ("PITCH", [V(x)]) => 0xF03A | (*x as u16) << 8,
+        (mnemonic, operands) => return Err(format!("no {} instruction takes {:?}", mnemonic, operands)),
+    };
+    rom.extend_from_slice(&opcode.to_be_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble_rom;
+
+    #[test]
+    fn assembles_labels_and_comments() {
+        let source = "
+            ; draws a 0 then spins
+            start: LD I, 0x50
+                   LD V0, 8      ; x
+            loop:  DRW V0, V0, 5
+                   JP loop
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(rom, to_bytes(&[ld_i(0x50), ld_vx_byte(0, 8), drw(0, 0, 5), jp(0x204)]));
+    }
+
+    #[test]
+    fn round_trips_disassembly() {
+        let program = to_bytes(&[
+            cls(), call(0x20A), se_vx_vy(1, 2), sne_vx_byte(3, 0x7F), or(4, 5), shl(6, 7),
+            rnd(8, 0x0F), sknp(9), ld_vx_dt(0xA), ld_i_vx(0xB), ld_vx_i(0xC), jp_v0(0x300),
+            0x00FF, 0xF201, 0xF000, 0x1234, 0xF375,
+        ]);
+        let source: String = disassemble_rom(&program, 0x200)
+            .into_iter()
+            .map(|(_, _, mnemonic)| mnemonic + "\n")
+            .collect();
+        assert_eq!(assemble(&source).unwrap(), program);
+    }
+
+    #[test]
+    fn reports_the_failing_line() {
+        assert_eq!(assemble("CLS\nLD V0, 0x100").unwrap_err().line, 2);
+        assert_eq!(assemble("JP nowhere").unwrap_err().reason, "unknown label nowhere");
+        assert!(assemble("FOO V1").is_err());
+        assert!(assemble("a: CLS\na: CLS").is_err());
+    }
+
+    #[test]
+    fn builders_produce_exact_words() {
+        let cases = [
+            (cls(), 0x00E0), (ret(), 0x00EE), (jp(0x234), 0x1234), (call(0xABC), 0x2ABC),
+            (se_vx_byte(3, 0x1F), 0x331F), (sne_vx_byte(3, 0x1F), 0x431F), (se_vx_vy(1, 2), 0x5120),
+            (ld_vx_byte(3, 0x1F), 0x631F), (add_vx_byte(3, 0x1F), 0x731F), (ld_vx_vy(1, 2), 0x8120),
+            (or(1, 2), 0x8121), (and(1, 2), 0x8122), (xor(1, 2), 0x8123), (add_vx_vy(1, 2), 0x8124),
+            (sub(1, 2), 0x8125), (shr(1, 2), 0x8126), (subn(1, 2), 0x8127), (shl(1, 2), 0x812E),
+            (sne_vx_vy(1, 2), 0x9120), (ld_i(0x300), 0xA300), (jp_v0(0x300), 0xB300),
+            (rnd(7, 0x0F), 0xC70F), (drw(0, 1, 5), 0xD015), (skp(5), 0xE59E), (sknp(5), 0xE5A1),
+            (ld_vx_dt(2), 0xF207), (ld_vx_k(2), 0xF20A), (ld_dt_vx(2), 0xF215), (ld_st_vx(2), 0xF218),
+            (add_i_vx(2), 0xF21E), (ld_f_vx(2), 0xF229), (ld_b_vx(2), 0xF233), (ld_i_vx(2), 0xF255),
+            (ld_vx_i(2), 0xF265),
+        ];
+        for (word, expected) in cases {
+            assert_eq!(word, expected, "{:04X}", expected);
+        }
+        // Out-of-range arguments are masked, not carried into the next field
+        assert_eq!(jp(0xF234), 0x1234);
+        assert_eq!(drw(0x12, 0x34, 0x56), 0xD246);
+        assert_eq!(to_bytes(&[0x00E0, 0x1234]), [0x00, 0xE0, 0x12, 0x34]);
+    }
+}
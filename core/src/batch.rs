@@ -0,0 +1,96 @@
+// Headless regression runs over a list of ROMs, for CI sweeps.
+//
+// Manifest format: one ROM per line, whitespace separated:
+//   PATH FRAMES HASH [QUIRKS]
+// e.g. "tests/ibm.ch8 120 3f2a9c0e11d4b765 shift,wrap". FRAMES is how many 60Hz frames
+// to run with no keys held, HASH the expected Core::display_hash afterwards in hex, and
+// QUIRKS an optional comma separated list of quirk names for the frontend to switch on.
+// Blank lines and lines starting with # are skipped. Paths can't contain spaces.
+
+use std::fmt;
+
+use crate::{Chip8Error, CoreBuilder, Quirks};
+
+// CXNN seed every run gets, so ROMs that use it still hash the same each time
+pub const BATCH_SEED: u64 = 0;
+
+// One manifest line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchEntry {
+    pub path: String,
+    pub frames: usize,
+    pub hash: u64,
+    pub quirks: Vec<String>,
+}
+
+// A manifest line that couldn't be read, `line` counting from 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestParseError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ManifestParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "manifest line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for ManifestParseError {}
+
+pub fn parse_manifest(text: &str) -> Result<Vec<BatchEntry>, ManifestParseError> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |reason: String| ManifestParseError { line: index + 1, reason };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if !(3..=4).contains(&fields.len()) {
+            return Err(error(format!("expected PATH FRAMES HASH [QUIRKS], found {} fields", fields.len())));
+        }
+        let frames = fields[1].parse().map_err(|_| error(format!("{:?} isn't a frame count", fields[1])))?;
+        let hash = u64::from_str_radix(fields[2], 16).map_err(|_| error(format!("{:?} isn't a hex hash", fields[2])))?;
+        let quirks = fields.get(3).map_or(Vec::new(), |names| names.split(',').map(str::to_string).collect());
+        entries.push(BatchEntry { path: fields[0].to_string(), frames, hash, quirks });
+    }
+    Ok(entries)
+}
+
+// Runs `program` for `frames` frames at `cps` like Core::run_frames and returns the
+// display hash it ends on
+pub fn run_entry(program: &[u8], quirks: Quirks, cps: usize, frames: usize) -> Result<u64, Chip8Error> {
+    let mut core = CoreBuilder::new().program(program).quirks(quirks).seed(BATCH_SEED).build()?;
+    core.run_frames(cps, frames, &[])?;
+    Ok(core.display_hash())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::*;
+
+    #[test]
+    fn reads_a_manifest() {
+        let text = "# path frames hash quirks\n\nibm.ch8 120 00ff shift,wrap\n  other.ch8 1 0  \n";
+        let entries = parse_manifest(text).unwrap();
+        assert_eq!(entries[0], BatchEntry { path: "ibm.ch8".into(), frames: 120, hash: 0xFF, quirks: vec!["shift".into(), "wrap".into()] });
+        assert_eq!(entries[1], BatchEntry { path: "other.ch8".into(), frames: 1, hash: 0, quirks: Vec::new() });
+
+        let bad = parse_manifest("a.ch8 10\nb.ch8 ten 00").unwrap_err();
+        assert_eq!(bad.line, 1);
+        assert_eq!(parse_manifest("b.ch8 ten 00").unwrap_err().to_string(), "manifest line 1: \"ten\" isn't a frame count");
+        assert!(parse_manifest("c.ch8 1 xyz").is_err());
+    }
+
+    #[test]
+    fn runs_are_repeatable_and_see_the_screen() {
+        // Draws a random digit, so the seed matters too
+        let program = to_bytes(&[rnd(0, 0xF), ld_f_vx(0), drw(1, 1, 5), jp(0x206)]);
+        let hash = run_entry(&program, Quirks::default(), 600, 2).unwrap();
+        assert_eq!(run_entry(&program, Quirks::default(), 600, 2), Ok(hash));
+        assert_ne!(run_entry(&[], Quirks::default(), 600, 2), Ok(hash));
+        assert_eq!(run_entry(&to_bytes(&[0xE000]), Quirks::default(), 600, 2), Err(Chip8Error::InvalidOpcode(0xE000)));
+    }
+}
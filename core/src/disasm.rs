@@ -0,0 +1,166 @@
+// Mnemonics follow Cowgod's reference, with the SUPER-CHIP and XO-CHIP extensions
+// named the way most assemblers spell them. The match mirrors Core::decode_and_exec,
+// so anything that decodes to an invalid opcode there comes out as UNKNOWN here.
+pub fn disassemble(opcode: u16) -> String {
+    let nibble = (opcode & 0xF000) >> 12;
+    let rest = opcode & 0x0FFF;
+    let x = (rest & 0xF00) >> 8;
+    let y = (rest & 0x0F0) >> 4;
+    let n = rest & 0x00F;
+    let nn = rest & 0x0FF;
+
+    match nibble {
+        0x0 => match rest {
+            0x000 => "NOP".to_string(),
+            0x0C0..=0x0CF => format!("SCD {}", n),
+            0x0D0..=0x0DF => format!("SCU {}", n),
+            0x0E0 => "CLS".to_string(),
+            0x0EE => "RET".to_string(),
+            0x0FB => "SCR".to_string(),
+            0x0FC => "SCL".to_string(),
+            0x0FD => "EXIT".to_string(),
+            0x0FE => "LOW".to_string(),
+            0x0FF => "HIGH".to_string(),
+            _ => unknown(opcode),
+        },
+        0x1 => format!("JP {:#05X}", rest),
+        0x2 => format!("CALL {:#05X}", rest),
+        0x3 => format!("SE V{:X}, {:#04X}", x, nn),
+        0x4 => format!("SNE V{:X}, {:#04X}", x, nn),
+        0x5 => format!("SE V{:X}, V{:X}", x, y),
+        0x9 => format!("SNE V{:X}, V{:X}", x, y),
+        0x6 => format!("LD V{:X}, {:#04X}", x, nn),
+        0x7 => format!("ADD V{:X}, {:#04X}", x, nn),
+        0x8 => match n {
+            0x0 => format!("LD V{:X}, V{:X}", x, y),
+            0x1 => format!("OR V{:X}, V{:X}", x, y),
+            0x2 => format!("AND V{:X}, V{:X}", x, y),
+            0x3 => format!("XOR V{:X}, V{:X}", x, y),
+            0x4 => format!("ADD V{:X}, V{:X}", x, y),
+            0x5 => format!("SUB V{:X}, V{:X}", x, y),
+            0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+            0x6 => format!("SHR V{:X}, V{:X}", x, y),
+            0xE => format!("SHL V{:X}, V{:X}", x, y),
+            _ => unknown(opcode),
+        },
+        0xA => format!("LD I, {:#05X}", rest),
+        // Read as BXNN under the jump quirk, but the plain form is the common one
+        0xB => format!("JP V0, {:#05X}", rest),
+        0xC => format!("RND V{:X}, {:#04X}", x, nn),
+        0xD => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE => match nn {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => unknown(opcode),
+        },
+        0xF => match nn {
+            // The address is in the following word
+            0x00 if rest == 0x000 => "LD I, LONG".to_string(),
+            0x01 => format!("PLANE {}", x),
+            0x02 => "AUDIO".to_string(),
+            0x07 => format!("LD V{:X}, DT", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x30 => format!("LD HF, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x3A => format!("PITCH V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            0x75 => format!("LD R, V{:X}", x),
+            0x85 => format!("LD V{:X}, R", x),
+            _ => unknown(opcode),
+        },
+        _ => unknown(opcode),
+    }
+}
+
+fn unknown(opcode: u16) -> String {
+    format!("UNKNOWN {:#06X}", opcode)
+}
+
+// One (address, opcode, mnemonic) per instruction starting at `base`, normally 0x200.
+// F000 NNNN takes up both its words with the address filled in, and a trailing odd byte
+// comes out as a DB data line with the byte as its opcode.
+pub fn disassemble_rom(program: &[u8], base: u16) -> Vec<(u16, u16, String)> {
+    let mut listing = Vec::with_capacity(program.len() / 2 + 1);
+    let mut offset = 0;
+    while offset < program.len() {
+        let addr = base.wrapping_add(offset as u16);
+        let Some(word) = program.get(offset..offset + 2) else {
+            listing.push((addr, program[offset] as u16, format!("DB {:#04X}", program[offset])));
+            break;
+        };
+        let opcode = u16::from_be_bytes([word[0], word[1]]);
+        offset += 2;
+
+        let mnemonic = match program.get(offset..offset + 2) {
+            Some(long) if opcode == 0xF000 => {
+                offset += 2;
+                format!("LD I, {:#06X}", u16::from_be_bytes([long[0], long[1]]))
+            }
+            _ => disassemble(opcode),
+        };
+        listing.push((addr, opcode, mnemonic));
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassembles_each_nibble_group() {
+        let cases = [
+            (0x00E0, "CLS"),
+            (0x00C4, "SCD 4"),
+            (0x1234, "JP 0x234"),
+            (0x2ABC, "CALL 0xABC"),
+            (0x3A12, "SE VA, 0x12"),
+            (0x4B01, "SNE VB, 0x01"),
+            (0x5120, "SE V1, V2"),
+            (0x631F, "LD V3, 0x1F"),
+            (0x7EFF, "ADD VE, 0xFF"),
+            (0x8234, "ADD V2, V3"),
+            (0x801E, "SHL V0, V1"),
+            (0x9340, "SNE V3, V4"),
+            (0xA050, "LD I, 0x050"),
+            (0xB300, "JP V0, 0x300"),
+            (0xC70F, "RND V7, 0x0F"),
+            (0xD015, "DRW V0, V1, 5"),
+            (0xE59E, "SKP V5"),
+            (0xF20A, "LD V2, K"),
+            (0xF000, "LD I, LONG"),
+            (0xF965, "LD V9, [I]"),
+        ];
+        for (opcode, mnemonic) in cases {
+            assert_eq!(disassemble(opcode), mnemonic, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn unknown_opcodes_fall_back() {
+        for opcode in [0x0123, 0x8008, 0x800F, 0xE000, 0xF1FF] {
+            assert_eq!(disassemble(opcode), format!("UNKNOWN {:#06X}", opcode));
+        }
+    }
+
+    #[test]
+    fn lists_a_rom_with_addresses() {
+        // CLS, LD I long, DRW, then a stray trailing byte
+        let program = [0x00, 0xE0, 0xF0, 0x00, 0x12, 0x34, 0xD0, 0x15, 0xAB];
+        let listing = disassemble_rom(&program, 0x200);
+        assert_eq!(listing, [
+            (0x200, 0x00E0, "CLS".to_string()),
+            (0x202, 0xF000, "LD I, 0x1234".to_string()),
+            (0x206, 0xD015, "DRW V0, V1, 5".to_string()),
+            (0x208, 0x00AB, "DB 0xAB".to_string()),
+        ]);
+        // F000 as the last word has no address to show
+        assert_eq!(disassemble_rom(&[0xF0, 0x00], 0x300), [(0x300, 0xF000, "LD I, LONG".to_string())]);
+        assert!(disassemble_rom(&[], 0x200).is_empty());
+    }
+}
@@ -1,44 +1,666 @@
-use std::collections::VecDeque;
-use rand::random;
+pub mod asm;
+pub mod batch;
+pub mod disasm;
+pub mod opcodes;
+pub mod quirkdiff;
+pub mod reftrace;
+pub mod runner;
+mod snapshot;
 
-const RAM_SIZE: usize = 4096;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::BuildHasher;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::io::{self, Read};
+
+// XO-CHIP's full 64KB address space, plain CHIP-8 programs only use the first 4KB
+const RAM_SIZE: usize = 0x10000;
 const NUM_REG: usize = 16;
+// Instructions kept by the trace, the oldest fall off first
+const TRACE_LEN: usize = 1024;
+// SUPER-CHIP's HP48 "RPL user flags", only V0..V7 fit
+const NUM_RPL_FLAGS: usize = 8;
+// XO-CHIP's 1-bit audio pattern, 128 samples played MSB first
+const AUDIO_BUFFER_SIZE: usize = 16;
+// FX3A pitch that plays the pattern at 4000 samples a second
+const DEFAULT_AUDIO_PITCH: u8 = 64;
 const START_ADDR: u16 = 0x200;
+// Deepest call nesting supported, matching the 16 return addresses most interpreters keep
+pub const MAX_STACK: usize = 16;
+
+pub const SCREEN_WIDTH: usize = 64;
+pub const SCREEN_HEIGHT: usize = 32;
+// SUPER-CHIP high resolution mode, toggled with 00FF/00FE
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+// XO-CHIP bit planes, FN01 picks which ones drawing and clearing apply to
+const NUM_PLANES: usize = 2;
+
+// One bit plane's pixels, a u128 per row with bit x for column x. Sized for hires, lores
+// only uses the low SCREEN_WIDTH bits of the first SCREEN_HEIGHT rows.
+type Plane = [u128; HIRES_HEIGHT];
+
+// Rate the delay and sound timers count down at
+pub const TIMER_HZ: usize = 60;
+
+// Number of cycles to run in the given 60Hz frame so that `cps` is hit exactly over
+// each second, spreading the remainder when cps isn't a multiple of 60
+pub fn frame_cycles(cps: usize, frame: usize) -> usize {
+    let frame = frame % TIMER_HZ;
+    (frame + 1) * cps / TIMER_HZ - frame * cps / TIMER_HZ
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip8Error {
+    // The instruction doesn't decode to anything this interpreter implements
+    InvalidOpcode(u16),
+    // 00EE with nothing on the call stack
+    StackUnderflow,
+    // 2NNN with MAX_STACK return addresses already pushed
+    StackOverflow,
+    // Core::from_bytes was given something that isn't a complete snapshot of this version
+    BadSnapshot,
+    // The program doesn't fit between 0x200 and the top of RAM
+    RomTooLarge { size: usize, max: usize },
+    // CoreBuilder was given quirks that can't be combined, see Quirks::validate
+    IncompatibleQuirks(&'static str),
+    // Reading the ROM failed, only the kind is kept so the error stays Copy
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::InvalidOpcode(op) => write!(f, "invalid opcode {:#06X}", op),
+            Chip8Error::StackUnderflow => write!(f, "stack underflow: return with an empty call stack"),
+            Chip8Error::StackOverflow => write!(f, "stack overflow: more than {} nested calls", MAX_STACK),
+            Chip8Error::BadSnapshot => write!(f, "not a valid save state"),
+            Chip8Error::RomTooLarge { size, max } => write!(f, "ROM is {} bytes but at most {} fit in memory", size, max),
+            Chip8Error::IncompatibleQuirks(reason) => write!(f, "incompatible quirks: {}", reason),
+            Chip8Error::Io(kind) => write!(f, "couldn't read ROM: {}", kind),
+        }
+    }
+}
+
+impl Error for Chip8Error {}
+
+// Why run_frame returned control to the frontend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOutcome {
+    // The full cycle budget ran
+    BudgetExhausted,
+    // An FX0A is parked waiting for a key, so more cycles this frame would just spin
+    AwaitingKey,
+    // A DXYN under the display-wait quirk has to wait for the next frame
+    DisplayWait,
+    // The program exited with 00FD, further cycles do nothing
+    Halted,
+    // The program sits in a jump-to-self, see Core::is_idle
+    Idle,
+}
+
+// What a single cycle_checked did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CycleOutcome {
+    Executed,
+    // pc reached a breakpoint, the instruction there hasn't run yet
+    BreakpointHit(u16),
+    // the instruction that just ran wrote into a watched range (the first such byte)
+    WatchpointHit { addr: u16, old: u8, new: u8 },
+}
+
+// One executed instruction in the trace, recorded before it ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u16,
+}
+
+impl TraceEntry {
+    // Disassembled on demand so tracing itself stays cheap
+    pub fn mnemonic(&self) -> String {
+        disasm::disassemble(self.opcode)
+    }
+}
+
+// Interpreter behaviors that differ between CHIP-8 implementations. The default is the
+// modern (CHIP-48/SCHIP-era) behavior most ROMs today expect, with XO-CHIP's additions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Quirks {
+    // 8XY6/8XYE copy VY into VX before shifting
+    pub shift_uses_vy: bool,
+    // FX55/FX65 leave I pointing past the last register stored/loaded
+    pub load_store_increments_i: bool,
+    // BNNN jumps to XNN plus VX instead of NNN plus V0
+    pub jump_offset_uses_vx: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+    // sprites running off the right/bottom edge wrap around instead of being clipped
+    pub sprite_wrapping: bool,
+    // DXYN waits for the next vertical blank, limiting draws to one per frame
+    pub display_wait: bool,
+    // mask I to 12 bits on every write instead of keeping the full 16 bits
+    pub i_mask_12bit: bool,
+    // decode XO-CHIP's F000 NNNN, FN01, F002 and FX3A, otherwise they're invalid opcodes
+    pub xo_chip: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_offset_uses_vx: false,
+            logic_resets_vf: false,
+            sprite_wrapping: false,
+            display_wait: false,
+            i_mask_12bit: false,
+            xo_chip: true,
+        }
+    }
+}
+
+impl Quirks {
+    // Programs written for the original COSMAC VIP interpreter
+    pub fn cosmac_vip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_offset_uses_vx: false,
+            logic_resets_vf: true,
+            sprite_wrapping: false,
+            display_wait: true,
+            i_mask_12bit: false,
+            xo_chip: false,
+        }
+    }
+
+    // Human-readable name for the preset these quirks match, Custom for anything else
+    pub fn profile_name(&self) -> &'static str {
+        if *self == Self::cosmac_vip() {
+            "COSMAC VIP"
+        } else if *self == Self::default() {
+            "Modern"
+        } else {
+            "Custom"
+        }
+    }
+
+    // Catches settings that can't work together, so a typo'd configuration fails up
+    // front instead of misbehaving once the ROM hits the affected instruction
+    pub fn validate(&self) -> Result<(), String> {
+        match self.conflict() {
+            Some(reason) => Err(reason.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    // Every known incompatible combination. The others are independent of each other.
+    fn conflict(&self) -> Option<&'static str> {
+        if self.i_mask_12bit && self.xo_chip {
+            // F000 would silently truncate, e.g. F000 1234 leaving I at 0x234
+            return Some("i_mask_12bit can't be combined with xo_chip: F000 NNNN and XO-CHIP's 64KB of memory need all 16 bits of I");
+        }
+        None
+    }
+}
+
+// Just the CPU side of the machine, no RAM or display, for cheap resets between
+// benchmark iterations
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    pub pc: u16,
+    pub i_reg: u16,
+    pub v_reg: [u8; NUM_REG],
+    pub d_timer: u8,
+    pub s_timer: u8,
+    pub stack: [u16; MAX_STACK],
+    // number of return addresses in use, stack[sp - 1] is the top
+    pub sp: usize,
+}
+
+// Everything needed to put a Core back exactly where it was, for save slots. Settings
+// the frontend owns (min sound ticks, key index masking, always-hires) aren't included.
+// With the serde feature it can be written out in any serde format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CoreState {
+    pub cpu: CpuState,
+    #[cfg_attr(feature = "serde", serde(with = "serde_arrays::boxed"))]
+    pub ram: Box<[u8; RAM_SIZE]>,
+    #[cfg_attr(feature = "serde", serde(with = "serde_arrays"))]
+    pub display: Plane,
+    #[cfg_attr(feature = "serde", serde(with = "serde_arrays"))]
+    pub second_plane: Plane,
+    pub selected_planes: u8,
+    pub hires: bool,
+    pub quirks: Quirks,
+    pub halted: bool,
+    pub rpl_flags: [u8; NUM_RPL_FLAGS],
+    pub audio_buffer: [u8; AUDIO_BUFFER_SIZE],
+    pub audio_pitch: u8,
+    pub instruction_count: u64,
+    // CXNN's generator, so draws after a restore match the ones after the save
+    pub rng_state: u64,
+}
+
+// serde only derives arrays up to 32 long, so RAM and the planes go through here as
+// plain sequences
+#[cfg(feature = "serde")]
+mod serde_arrays {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer, T: Serialize, const N: usize>(array: &[T; N], serializer: S) -> Result<S::Ok, S::Error> {
+        array[..].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>, const N: usize>(deserializer: D) -> Result<[T; N], D::Error> {
+        let items = Vec::<T>::deserialize(deserializer)?;
+        let len = items.len();
+        items.try_into().map_err(|_| D::Error::invalid_length(len, &N.to_string().as_str()))
+    }
+
+    // Keeps RAM off the stack on the way back in
+    pub mod boxed {
+        use super::*;
 
-const SCREEN_WIDTH: usize = 64;
-const SCREEN_HEIGHT: usize = 32;
+        pub fn serialize<S: Serializer, T: Serialize, const N: usize>(array: &[T; N], serializer: S) -> Result<S::Ok, S::Error> {
+            super::serialize(array, serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>, const N: usize>(deserializer: D) -> Result<Box<[T; N]>, D::Error> {
+            let items = Vec::<T>::deserialize(deserializer)?.into_boxed_slice();
+            let len = items.len();
+            items.try_into().map_err(|_| D::Error::invalid_length(len, &N.to_string().as_str()))
+        }
+    }
+}
 
 pub struct Core {
     pc: u16,
     ram: [u8; RAM_SIZE],
-    stack: VecDeque<u16>,
-    pub display: [bool; SCREEN_WIDTH * SCREEN_HEIGHT],
+    stack: [u16; MAX_STACK],
+    sp: usize,
+    // XO-CHIP's first plane, the only one plain CHIP-8 and SUPER-CHIP programs draw to.
+    // Frontends read it through pixel or pixel_color.
+    display: Plane,
+    second_plane: Plane,
+    // bitmask of the planes drawing and clearing apply to, bit 0 is `display`
+    selected_planes: u8,
+    hires: bool,
     d_timer: u8,
     s_timer: u8,
     i_reg: u16,
     v_reg: [u8; NUM_REG],
-    // legacy mode for programs written for original COSMAC VIP interpreter
-    legacy: bool,
+    quirks: Quirks,
+    // shown by frontends, Quirks::profile_name unless the builder set one
+    profile_name: String,
+    // FX18 writes below this are bumped up so short beeps last long enough to be heard
+    min_sound_ticks: u8,
+    // EX9E/EXA1 test key VX & 0xF, instead of treating VX above F as never pressed
+    mask_key_index: bool,
+    // frontends always see 128x64, with each lores pixel covering 2x2
+    always_hires: bool,
+    // (x, y, width, height) of the most recent DXYN, before any clipping
+    last_draw: Option<(u8, u8, u8, u8)>,
+    // pixels the most recent DXYN turned off, summed over the planes it drew to
+    last_collisions: u16,
+    // inclusive (min_x, min_y, max_x, max_y) of pixels changed since take_dirty_region
+    dirty: Option<(usize, usize, usize, usize)>,
+    // set by anything that changes a pixel, until the frontend calls clear_dirty
+    display_dirty: bool,
+    // total instructions executed, frontends diff this per frame
+    instruction_count: u64,
+    // set while the last FX0A found no key and rewound pc
+    awaiting_key: bool,
+    // key FX0A saw go down, it's only stored once released
+    pending_key: Option<u8>,
+    // set by the first DXYN, or a clear that actually blanked something
+    has_drawn: bool,
+    // set by DXYN under the display-wait quirk until the frontend takes it
+    draw_pending: bool,
+    // set by the SUPER-CHIP exit instruction 00FD, cleared by reset
+    halted: bool,
+    // set by a 1NNN jumping to itself, see is_idle for why that alone isn't enough
+    idle: bool,
+    // written by FX75 and read back by FX85, kept across resets like on the HP48
+    rpl_flags: [u8; NUM_RPL_FLAGS],
+    // addresses cycle_checked stops at before executing
+    breakpoints: HashSet<u16>,
+    // breakpoint just reported, the next cycle_checked runs it instead of stopping again.
+    // Cleared by any executed instruction.
+    resume_from: Option<u16>,
+    // inclusive (start, end) RAM ranges that report instruction writes
+    watchpoints: Vec<(u16, u16)>,
+    // first watched write of the current instruction as (addr, old, new)
+    watch_hit: Option<(u16, u8, u8)>,
+    // None while tracing is off. Grows to twice TRACE_LEN before the older half is
+    // dropped, so trace() can always hand out a plain slice.
+    trace: Option<Vec<TraceEntry>>,
+    // CXNN draws from this, seeded from the OS unless with_seed picked the seed
+    rng: Xorshift,
+    // called with the new state whenever the sound timer starts or stops
+    sound_callback: Option<Box<dyn FnMut(bool) + Send>>,
+    // one bit per RAM address fetched as part of an instruction since the last reset,
+    // only tracked once on_self_modify is set
+    fetched: Option<Box<[u64; RAM_SIZE / 64]>>,
+    // called with the address of each write over a fetched address
+    self_modify_callback: Option<Box<dyn FnMut(u16) + Send>>,
+    // XO-CHIP waveform loaded by FX02, looped while the sound timer runs
+    audio_buffer: [u8; AUDIO_BUFFER_SIZE],
+    // set by FX3A
+    audio_pitch: u8,
+}
+
+// Chainable setup for a Core with non-default options, e.g.
+// CoreBuilder::new().program(&rom).quirks(Quirks::cosmac_vip()).trace(true).build()
+#[derive(Debug, Clone, Default)]
+pub struct CoreBuilder {
+    program: Vec<u8>,
+    quirks: Quirks,
+    min_sound_ticks: u8,
+    mask_key_index: bool,
+    always_hires: bool,
+    profile_name: Option<String>,
+    trace: bool,
+    seed: Option<u64>,
+}
+
+impl CoreBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn program(mut self, program: &[u8]) -> Self {
+        self.program = program.to_vec();
+        self
+    }
+
+    // Same meaning as Core::new's flag, only the shift quirk. Combined with quirks,
+    // whichever comes last decides shift_uses_vy.
+    pub fn legacy(mut self, legacy: bool) -> Self {
+        self.quirks.shift_uses_vy = legacy;
+        self
+    }
+
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn min_sound_ticks(mut self, ticks: u8) -> Self {
+        self.min_sound_ticks = ticks;
+        self
+    }
+
+    // Overrides the name that would otherwise come from Quirks::profile_name
+    pub fn profile_name(mut self, name: &str) -> Self {
+        self.profile_name = Some(name.to_string());
+        self
+    }
+
+    pub fn mask_key_index(mut self, enabled: bool) -> Self {
+        self.mask_key_index = enabled;
+        self
+    }
+
+    pub fn always_hires(mut self, enabled: bool) -> Self {
+        self.always_hires = enabled;
+        self
+    }
+
+    // Sets Quirks::i_mask_12bit, whichever of this and quirks comes last wins like legacy
+    pub fn i_mask_12bit(mut self, enabled: bool) -> Self {
+        self.quirks.i_mask_12bit = enabled;
+        self
+    }
+
+    pub fn trace(mut self, enabled: bool) -> Self {
+        self.trace = enabled;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    // Fails with IncompatibleQuirks if Quirks::validate rejects the quirks
+    pub fn build(self) -> Result<Core, Chip8Error> {
+        if let Some(reason) = self.quirks.conflict() {
+            return Err(Chip8Error::IncompatibleQuirks(reason));
+        }
+        let mut core = Core::try_with_quirks(&self.program, self.quirks)?;
+        core.set_min_sound_ticks(self.min_sound_ticks);
+        core.set_mask_key_index(self.mask_key_index);
+        core.set_always_hires(self.always_hires);
+        if let Some(name) = self.profile_name {
+            core.profile_name = name;
+        }
+        core.enable_trace(self.trace);
+        if let Some(seed) = self.seed {
+            core.rng = Xorshift::seeded(seed);
+        }
+        Ok(core)
+    }
+}
+
+// CXNN's generator, xorshift64* (Marsaglia's xorshift with Vigna's multiplier, shifts
+// 12/25/27). It's written out here instead of coming from a crate so a seed gives the
+// same numbers on every version, which replays and lockstep runs rely on. Changing it
+// changes the outcome of every seeded run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Xorshift {
+    // never 0, which xorshift can't leave
+    state: u64,
+}
+
+impl Xorshift {
+    // The seed goes through one round of splitmix64 so nearby seeds start far apart
+    fn seeded(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        Self::from_state(z)
+    }
+
+    // A saved state, with 0 swapped for a fixed non-zero one
+    fn from_state(state: u64) -> Self {
+        Self { state: if state == 0 { 0x9E3779B97F4A7C15 } else { state } }
+    }
+
+    // std's randomly keyed hasher is the one source of OS randomness without a crate
+    fn from_os() -> Self {
+        Self::seeded(RandomState::new().hash_one(0u64))
+    }
+
+    // The top byte of the output, xorshift's low bits being its weakest
+    fn next_byte(&mut self) -> u8 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        (x.wrapping_mul(0x2545F4914F6CDD1D) >> 56) as u8
+    }
+}
+
+// Slice of held CHIP-8 key values to the cycle_keys bitmask, ignoring anything above F
+fn key_mask(keys: &[u8]) -> u16 {
+    keys.iter().filter(|&&key| key < 16).fold(0, |mask, &key| mask | 1 << key)
+}
+
+fn check_rom_size(program: &[u8]) -> Result<(), Chip8Error> {
+    let max = RAM_SIZE - START_ADDR as usize;
+    if program.len() > max {
+        return Err(Chip8Error::RomTooLarge { size: program.len(), max });
+    }
+    Ok(())
+}
+
+fn get_pixel(plane: &Plane, x: usize, y: usize) -> bool {
+    plane[y] & (1 << x) != 0
+}
+
+// Flips a pixel and returns whether it was on before
+fn toggle_pixel(plane: &mut Plane, x: usize, y: usize) -> bool {
+    let was_on = get_pixel(plane, x, y);
+    plane[y] ^= 1 << x;
+    was_on
+}
+
+// Widens an inclusive (min_x, min_y, max_x, max_y) box to take in another one
+fn grow_region(region: &mut Option<(usize, usize, usize, usize)>, add: (usize, usize, usize, usize)) {
+    *region = Some(match *region {
+        Some((min_x, min_y, max_x, max_y)) => {
+            (min_x.min(add.0), min_y.min(add.1), max_x.max(add.2), max_y.max(add.3))
+        }
+        None => add,
+    });
 }
 
 impl Core {
+    // Legacy mode only switches on the shift quirk, use with_quirks for finer control.
+    // Panics if the program is too large, see try_new.
     pub fn new(program: &[u8], legacy: bool) -> Self {
+        Self::try_new(program, legacy).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn with_quirks(program: &[u8], quirks: Quirks) -> Self {
+        Self::try_with_quirks(program, quirks).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    // Like new, but an oversized program is a RomTooLarge error instead of a panic
+    pub fn try_new(program: &[u8], legacy: bool) -> Result<Self, Chip8Error> {
+        let quirks = Quirks { shift_uses_vy: legacy, ..Quirks::default() };
+        Self::try_with_quirks(program, quirks)
+    }
+
+    // Same as new but with CXNN's random numbers seeded, so a run can be reproduced
+    pub fn with_seed(program: &[u8], legacy: bool, seed: u64) -> Self {
+        let mut core = Self::new(program, legacy);
+        core.rng = Xorshift::seeded(seed);
+        core
+    }
+
+    // Assembles `source` (see asm::assemble) and builds a default core running it
+    pub fn from_asm(source: &str) -> Result<Self, asm::AsmError> {
+        let program = asm::assemble(source)?;
+        // assemble already refuses anything that runs past the end of RAM
+        Ok(Self::try_new(&program, false).expect("assembled program fits in RAM"))
+    }
+
+    // Reads the whole ROM from `reader` then builds the core like try_new
+    pub fn from_reader<R: Read>(mut reader: R, legacy: bool) -> Result<Self, Chip8Error> {
+        let mut program = Vec::new();
+        reader.read_to_end(&mut program).map_err(|err| Chip8Error::Io(err.kind()))?;
+        Self::try_new(&program, legacy)
+    }
+
+    pub fn try_with_quirks(program: &[u8], quirks: Quirks) -> Result<Self, Chip8Error> {
+        check_rom_size(program)?;
         let mut new_core = Self {
             pc: START_ADDR,
             ram: [0; RAM_SIZE],
-            stack: VecDeque::new(),
-            display: [false; SCREEN_WIDTH * SCREEN_HEIGHT],
+            stack: [0; MAX_STACK],
+            sp: 0,
+            display: [0; HIRES_HEIGHT],
+            second_plane: [0; HIRES_HEIGHT],
+            selected_planes: 1,
+            hires: false,
             d_timer: 0,
             s_timer: 0,
             i_reg: 0,
             v_reg: [0; NUM_REG],
-            legacy: legacy,
+            quirks,
+            profile_name: quirks.profile_name().to_string(),
+            min_sound_ticks: 0,
+            mask_key_index: false,
+            always_hires: false,
+            last_draw: None,
+            last_collisions: 0,
+            dirty: None,
+            display_dirty: false,
+            instruction_count: 0,
+            awaiting_key: false,
+            pending_key: None,
+            has_drawn: false,
+            draw_pending: false,
+            halted: false,
+            idle: false,
+            rpl_flags: [0; NUM_RPL_FLAGS],
+            breakpoints: HashSet::new(),
+            resume_from: None,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            trace: None,
+            rng: Xorshift::from_os(),
+            sound_callback: None,
+            fetched: None,
+            self_modify_callback: None,
+            audio_buffer: [0; AUDIO_BUFFER_SIZE],
+            audio_pitch: DEFAULT_AUDIO_PITCH,
         };
         new_core.load_sprites();
         new_core.load_rom(program);
-        new_core
+        Ok(new_core)
+    }
+
+    // Restarts the loaded program: CPU state, display and fonts go back to power-on values
+    // while the ROM bytes stay in RAM. Settings like the quirks are kept.
+    pub fn reset(&mut self) {
+        self.pc = START_ADDR;
+        self.stack = [0; MAX_STACK];
+        self.sp = 0;
+        self.display = [0; HIRES_HEIGHT];
+        self.second_plane = [0; HIRES_HEIGHT];
+        self.selected_planes = 1;
+        self.hires = false;
+        self.d_timer = 0;
+        self.write_sound_timer(0);
+        self.i_reg = 0;
+        self.v_reg = [0; NUM_REG];
+        self.last_draw = None;
+        self.last_collisions = 0;
+        self.mark_all_dirty();
+        self.instruction_count = 0;
+        self.awaiting_key = false;
+        self.pending_key = None;
+        self.resume_from = None;
+        self.has_drawn = false;
+        self.draw_pending = false;
+        self.halted = false;
+        self.idle = false;
+        self.audio_buffer = [0; AUDIO_BUFFER_SIZE];
+        self.audio_pitch = DEFAULT_AUDIO_PITCH;
+        self.clear_fetched();
+        self.load_sprites();
+    }
+
+    // Like reset, but also wipes RAM and loads a fresh program. An oversized program is
+    // rejected before anything is touched.
+    pub fn reset_and_reload(&mut self, program: &[u8]) -> Result<(), Chip8Error> {
+        check_rom_size(program)?;
+        self.ram = [0; RAM_SIZE];
+        self.reset();
+        self.load_rom(program);
+        Ok(())
+    }
+
+    // Assembles `source` and swaps it in like reset_and_reload. On an error the current
+    // program stays loaded.
+    pub fn load_asm(&mut self, source: &str) -> Result<(), asm::AsmError> {
+        let program = asm::assemble(source)?;
+        self.reset_and_reload(&program).expect("assembled program fits in RAM");
+        Ok(())
     }
 
+    // Callers check the size first with check_rom_size
     fn load_rom(&mut self, program: &[u8]) {
         // 0x200 is where instructions start in chip8
         // From 0x200 to the end of program length in ram, copy in the program
@@ -64,332 +686,2497 @@ impl Core {
             0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
             0xF0, 0x80, 0xF0, 0x80, 0x80] // F
         );
+        // SUPER-CHIP's 8x10 digits for FX30, straight after the small font
+        self.ram[0xA0..0x104].copy_from_slice(
+            &[0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+            0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+            0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+            0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+            0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+            0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+            0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C] // 9
+        );
     }
 
     pub fn decrement_timers(&mut self) {
         if self.d_timer > 0 {self.d_timer -= 1};
-        if self.s_timer > 0 {self.s_timer -= 1};
+        if self.s_timer > 0 {self.write_sound_timer(self.s_timer - 1)};
     }
 
-    pub fn sound_active(&self) -> bool {
-        self.s_timer > 0
+    pub fn tick_timers(&mut self, ticks: u32) {
+        for _ in 0..ticks {
+            self.decrement_timers();
+        }
     }
 
-    pub fn cycle(&mut self, keys: &[u8]) {
-        let instruction = self.fetch();
-        self.decode_and_exec(instruction, keys);
+    // Virtual clock: runs `frames` 60Hz frames at `cps` with exactly one timer tick
+    // after each frame's cycles, so timer-gated logic is fully reproducible
+    pub fn run_frames(&mut self, cps: usize, frames: usize, keys: &[u8]) -> Result<(), Chip8Error> {
+        for frame in 0..frames {
+            for _ in 0..frame_cycles(cps, frame) {
+                self.cycle(keys)?;
+            }
+            self.tick_timers(1);
+        }
+        Ok(())
     }
 
-    fn fetch(&mut self) -> u16 {
-        // Each instruction takes two bytes
-        let mut high_byte: u16 = self.ram[self.pc as usize].into();
-        let low_byte: u16 = self.ram[self.pc as usize + 1].into();
-        
-        // Increment pc here to avoid having to do this later
-        self.pc += 2;
-        high_byte = high_byte << 8;
-        high_byte + low_byte
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
     }
 
-    fn decode_and_exec(&mut self, instruction: u16, keys: &[u8]) {
-        // 1st 4 bit "nibble"
-        let nibble = (instruction & 0xF000) >> 12;
-        let rest = instruction & 0x0FFF;
-        match nibble {
-            // Some instructions out of order to group similar opcodes
-            0x0 => match rest {
-                    0x000 => self.noop(),
-                    0x0E0 => self.clear_screen(),
-                    0x0EE => self.ret_subroutine(),
-
-                    _ => {dbg!("invalid op"); dbg!(instruction);}
-                },
-            0x1 => self.jump(rest),
-            0x2 => self.call(rest),
-            0x3 => self.skip_eq_val(rest),
-            0x4 => self.skip_neq_val(rest),
-            0x5 => self.skip_eq_reg(rest),
-            0x9 => self.skip_neq_reg(rest),
-            0x6 => self.set_v(rest),
-            0x7 => self.add_v_no_carry(rest),
-            // Logical and arithmetic instructions determined by last nibble
-            0x8 => match rest & 0x00F {
-                0x0 => self.set(rest),
-                0x1 => self.or(rest),
-                0x2 => self.and(rest),
-                0x3 => self.xor(rest),
-                0x4 => self.add(rest),
-                //sub function handles which to exec
-                0x5 => self.sub(rest), //sub x-y
-                0x7 => self.sub(rest), //sub y-x
-                0x6 => self.right_shift(rest),
-                0xE => self.left_shift(rest),
-                _ => {dbg!("invalid op"); dbg!(instruction);}
-            }
-            0xA => self.set_i(rest),
-            0xB => self.jump_offset(rest),
-            0xC => self.rand(rest),
-            0xD => self.draw_sprite(rest),
-            0xE => self.key_skip(rest, keys), //skip if key
-            0xF => match rest & 0x0FF {
-                //timers
-                0x07 => self.v_reg[((rest & 0xF00) >> 8) as usize] = self.d_timer,
-                0x15 => self.d_timer = self.v_reg[((rest & 0xF00) >> 8) as usize],
-                0x18 => self.s_timer = self.v_reg[((rest & 0xF00) >> 8) as usize],
-                
-                0x0A => self.await_key(rest, keys),
-                0x1E => self.i_reg += self.v_reg[((rest & 0xF00) >> 8) as usize] as u16,
-                0x29 => self.set_i_font(rest),
-                0x33 => self.bcd(rest),
-                0x55 => self.store_mem(rest),
-                0x65 => self.fill_mem(rest),
-                _ => {dbg!("invalid op"); dbg!(instruction);}
-            },
+    // e.g. "COSMAC VIP" for the title bar, see CoreBuilder::profile_name
+    pub fn profile_name(&self) -> &str {
+        &self.profile_name
+    }
 
-            _ => {dbg!("invalid op"); dbg!(instruction);}
-        };
+    pub fn pc(&self) -> u16 {
+        self.pc
     }
 
-    //TODO ensure ordering of instructions based on opcode sheet
+    pub fn i(&self) -> u16 {
+        self.i_reg
+    }
 
-    fn noop(&self) {}
-    
-    fn clear_screen(&mut self) {
-        self.display = [false; SCREEN_WIDTH * SCREEN_HEIGHT];
+    pub fn registers(&self) -> &[u8; NUM_REG] {
+        &self.v_reg
     }
 
-    fn jump(&mut self, addr: u16) {
-        self.pc = addr;
+    pub fn delay_timer(&self) -> u8 {
+        self.d_timer
     }
 
-    fn call(&mut self, addr: u16) {
-        self.stack.push_back(self.pc);
-        self.pc = addr;
+    pub fn sound_timer(&self) -> u8 {
+        self.s_timer
     }
 
-    fn ret_subroutine(&mut self) {
-        self.pc = self.stack.pop_back().expect("Stack underflow: expected return address in stack");
+    // Size of the active display area as (width, height). Always 128x64 with always-hires
+    // on, which pixel, pixel_color, last_draw_rect and take_dirty_region all follow.
+    pub fn display_dimensions(&self) -> (usize, usize) {
+        if self.always_hires { (HIRES_WIDTH, HIRES_HEIGHT) } else { self.native_dimensions() }
     }
-    
-    fn skip_eq_val(&mut self, rest: u16) {
-        let x= ((rest & 0xF00) >> 8) as usize;
-        let val: u8 = ((rest & 0x0FF)) as u8;
 
-        if self.v_reg[x] == val {
-            self.pc += 2;
-        }
+    // The resolution the program is actually drawing at
+    fn native_dimensions(&self) -> (usize, usize) {
+        if self.hires { (HIRES_WIDTH, HIRES_HEIGHT) } else { (SCREEN_WIDTH, SCREEN_HEIGHT) }
     }
 
-    fn skip_neq_val(&mut self, rest: u16) {
-        let x= ((rest & 0xF00) >> 8) as usize;
-        let val: u8 = (rest & 0x0FF) as u8;
+    // Display pixels per native pixel along each side
+    fn output_scale(&self) -> usize {
+        if self.always_hires && !self.hires { 2 } else { 1 }
+    }
 
-        if self.v_reg[x] != val {
-            self.pc += 2;
-        }
+    pub fn is_hires(&self) -> bool {
+        self.hires
     }
 
-    fn skip_eq_reg(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let y = ((rest & 0x0F0) >> 4) as usize;
+    // 2-bit XO-CHIP color index of a pixel in the active display area, bit 0 from the
+    // first plane and bit 1 from the second
+    pub fn pixel_color(&self, x: usize, y: usize) -> u8 {
+        let (x, y) = (x / self.output_scale(), y / self.output_scale());
+        get_pixel(&self.display, x, y) as u8 | (get_pixel(&self.second_plane, x, y) as u8) << 1
+    }
 
-        if self.v_reg[x] == self.v_reg[y] {
-            self.pc += 2;
-        }
+    // 64-bit FNV-1a of the resolution and both planes, for checking a screen against a
+    // known good one (see batch). Stable across runs and builds, unlike std's hashers, and
+    // unaffected by always-hires.
+    pub fn display_hash(&self) -> u64 {
+        let rows = self.display.iter().chain(self.second_plane.iter()).flat_map(|row| row.to_le_bytes());
+        std::iter::once(self.hires as u8)
+            .chain(rows)
+            .fold(0xCBF2_9CE4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3))
     }
 
-    fn skip_neq_reg(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let y = ((rest & 0x0F0) >> 4) as usize;
+    // Whether a pixel of the first plane is on, in the active display area
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        let scale = self.output_scale();
+        get_pixel(&self.display, x / scale, y / scale)
+    }
 
-        if self.v_reg[x] != self.v_reg[y] {
-            self.pc += 2;
-        }
+    pub fn is_sound_active(&self) -> bool {
+        self.s_timer > 0
     }
 
-    fn set_v(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        self.v_reg[x] = (rest & 0xFF) as u8;
+    // The original name, kept for existing callers
+    pub fn sound_active(&self) -> bool {
+        self.is_sound_active()
     }
 
-    fn add_v_no_carry(&mut self, rest: u16){
-        let x = ((rest & 0xF00) >> 8) as usize;
-        self.v_reg[x] = self.v_reg[x].wrapping_add((rest & 0xFF) as u8);
+    // Lets a frontend start and stop its tone on transitions instead of polling
+    // is_sound_active every cycle. Replaces any earlier callback. The callback has to be
+    // Send so that Core stays Send and can still be handed to another thread.
+    pub fn on_sound_change(&mut self, f: impl FnMut(bool) + Send + 'static) {
+        self.sound_callback = Some(Box::new(f));
     }
 
-    fn set(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let y = ((rest & 0x0F0) >> 4) as usize;
-        self.v_reg[x] = self.v_reg[y];
+    // Flags self-modifying code: from now on every address fetched as an instruction is
+    // remembered, and `f` is called with the address of any later write to one of them,
+    // by the program or through write_byte. Writes through ram_mut aren't seen. Off by
+    // default since it costs a little on every fetch. Replaces any earlier callback.
+    pub fn on_self_modify(&mut self, f: impl FnMut(u16) + Send + 'static) {
+        self.fetched.get_or_insert_with(|| Box::new([0; RAM_SIZE / 64]));
+        self.self_modify_callback = Some(Box::new(f));
     }
 
-    fn or(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let y = ((rest & 0x0F0) >> 4) as usize;
-        self.v_reg[x] = self.v_reg[x] | self.v_reg[y];
+    // XO-CHIP pattern and pitch for frontends that synthesize it, see audio_sample_rate
+    pub fn audio_pattern(&self) -> (&[u8; AUDIO_BUFFER_SIZE], u8) {
+        (&self.audio_buffer, self.audio_pitch)
     }
 
-    fn and(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let y = ((rest & 0x0F0) >> 4) as usize;
-        self.v_reg[x] = self.v_reg[x] & self.v_reg[y];
+    // Samples per second to step through the pattern at for the current pitch
+    pub fn audio_sample_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.audio_pitch as f32 - 64.0) / 48.0)
     }
 
-    fn xor(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let y = ((rest & 0x0F0) >> 4) as usize;
-        self.v_reg[x] = self.v_reg[x] ^ self.v_reg[y];
+    // 0 (the default) keeps the authentic behavior
+    pub fn set_min_sound_ticks(&mut self, ticks: u8) {
+        self.min_sound_ticks = ticks;
     }
 
-    fn add(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let y = ((rest & 0x0F0) >> 4) as usize;
-        let sum = self.v_reg[x] as u16 + self.v_reg[y] as u16;
+    // Off by default, so EX9E never skips and EXA1 always does for VX above F. When on
+    // only the low nibble of VX picks the key, e.g. 0x23 tests key 3.
+    pub fn set_mask_key_index(&mut self, enabled: bool) {
+        self.mask_key_index = enabled;
+    }
 
-        self.v_reg[0xF] = if sum < 256 {0} else {1};
-        self.v_reg[x] = (sum & 0x00FF) as u8;
+    // Off by default. Lores programs still run at 64x32 with the same collisions, only
+    // what frontends see is scaled up, so they never have to follow resolution switches.
+    pub fn set_always_hires(&mut self, enabled: bool) {
+        if enabled != self.always_hires {
+            self.always_hires = enabled;
+            self.dirty = None;
+            self.mark_all_dirty();
+        }
     }
 
-    fn sub(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let y = ((rest & 0x0F0) >> 4) as usize;
+    // Machine state as a single JSON object for external tools:
+    // {"pc": u16, "i": u16, "v": [u8; 16], "delay_timer": u8, "sound_timer": u8,
+    //  "stack": [u16, ...] (oldest first), "ram": "<lowercase hex, 2 chars per byte>"}
+    pub fn state_json(&self) -> String {
+        let join = |items: Vec<String>| items.join(",");
+        let v = join(self.v_reg.iter().map(|r| r.to_string()).collect());
+        let stack = join(self.stack[..self.sp].iter().map(|a| a.to_string()).collect());
 
-        let minuend = if (rest & 0x00F) == 5 { x } else { y };
-        let subtrahend = if (rest & 0x00F) == 5 { y } else { x };
+        let mut ram = String::with_capacity(RAM_SIZE * 2);
+        for byte in self.ram.iter() {
+            write!(ram, "{:02x}", byte).unwrap();
+        }
 
-        // set VF to 1 if there's no borrow, i.e. left > right
-        self.v_reg[0xF] = if self.v_reg[minuend] >= self.v_reg[subtrahend] { 1 } else { 0 };
-        self.v_reg[x] = self.v_reg[minuend].wrapping_sub(self.v_reg[subtrahend]);
+        format!(
+            "{{\"pc\":{},\"i\":{},\"v\":[{}],\"delay_timer\":{},\"sound_timer\":{},\"stack\":[{}],\"ram\":\"{}\"}}",
+            self.pc, self.i_reg, v, self.d_timer, self.s_timer, stack, ram
+        )
     }
 
-    fn right_shift(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        if self.legacy {
-            let y: usize = ((rest & 0x0F0) >> 4) as usize;
-            self.v_reg[x] = self.v_reg[y];
+    // Human-readable summary for bug reports, always in this layout:
+    //   PC 0x0202  I 0x0300
+    //   V0-V7 01 02 00 00 00 00 00 00
+    //   V8-VF 00 00 00 00 00 00 00 01
+    //   DT 00  ST 00
+    //   stack 0x0204 0x0310 (oldest first, "-" when empty)
+    //   next 7002 ADD V0, 0x02
+    pub fn dump_state(&self) -> String {
+        let mut out = String::with_capacity(192);
+        writeln!(out, "PC {:#06X}  I {:#06X}", self.pc, self.i_reg).unwrap();
+        for (label, regs) in [("V0-V7", &self.v_reg[..8]), ("V8-VF", &self.v_reg[8..])] {
+            out.push_str(label);
+            for reg in regs {
+                write!(out, " {:02X}", reg).unwrap();
+            }
+            out.push('\n');
         }
-        // extract lsb
-        self.v_reg[0xF] = self.v_reg[x] & 1;
-        self.v_reg[x] >>= 1;
+        writeln!(out, "DT {:02X}  ST {:02X}", self.d_timer, self.s_timer).unwrap();
+        out.push_str("stack");
+        if self.sp == 0 {
+            out.push_str(" -");
+        }
+        for addr in &self.stack[..self.sp] {
+            write!(out, " {:#06X}", addr).unwrap();
+        }
+        let next = u16::from_be_bytes([self.read_ram(self.pc, 0), self.read_ram(self.pc, 1)]);
+        write!(out, "\nnext {:04X} {}", next, disasm::disassemble(next)).unwrap();
+        out
     }
 
-    fn left_shift(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        if self.legacy {
-            let y: usize = ((rest & 0x0F0) >> 4) as usize;
-            self.v_reg[x] = self.v_reg[y];
+    pub fn save_cpu(&self) -> CpuState {
+        CpuState {
+            pc: self.pc,
+            i_reg: self.i_reg,
+            v_reg: self.v_reg,
+            d_timer: self.d_timer,
+            s_timer: self.s_timer,
+            stack: self.stack,
+            sp: self.sp,
         }
-        // extract msb
-        self.v_reg[0xF] = self.v_reg[x] >> 7;
-        self.v_reg[x] <<= 1;
     }
 
-    fn set_i(&mut self, addr: u16) {
-        self.i_reg = addr;
+    // A stack pointer past the end of the stack is a BadSnapshot, and nothing is restored
+    pub fn restore_cpu(&mut self, state: CpuState) -> Result<(), Chip8Error> {
+        if state.sp > MAX_STACK {
+            return Err(Chip8Error::BadSnapshot);
+        }
+        self.pc = state.pc;
+        self.i_reg = state.i_reg;
+        self.v_reg = state.v_reg;
+        self.d_timer = state.d_timer;
+        self.write_sound_timer(state.s_timer);
+        self.stack = state.stack;
+        self.sp = state.sp;
+        self.idle = false;
+        Ok(())
     }
 
-    fn jump_offset(&mut self, rest: u16) {
-        self.pc = (self.v_reg[0] as u16) + rest;
+    pub fn save_state(&self) -> CoreState {
+        CoreState {
+            cpu: self.save_cpu(),
+            ram: Box::new(self.ram),
+            display: self.display,
+            second_plane: self.second_plane,
+            selected_planes: self.selected_planes,
+            hires: self.hires,
+            quirks: self.quirks,
+            halted: self.halted,
+            rpl_flags: self.rpl_flags,
+            audio_buffer: self.audio_buffer,
+            audio_pitch: self.audio_pitch,
+            instruction_count: self.instruction_count,
+            rng_state: self.rng.state,
+        }
     }
 
-    fn rand(&mut self, rest: u16) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let rand: u8 = random();
-        self.v_reg[x] = rand & ((rest & 0x0FF) as u8);
+    // Anything in flight mid-frame (a pending FX0A or display wait) is dropped, and the
+    // restored screen counts as drawn so the frontend presents it. A state no Core could
+    // have saved (see from_bytes) is a BadSnapshot and leaves this one untouched.
+    pub fn load_state(&mut self, state: CoreState) -> Result<(), Chip8Error> {
+        // xorshift never reaches 0, so a 0 wasn't written by save_state
+        if state.cpu.sp > MAX_STACK
+            || state.selected_planes > 0b11
+            || state.rng_state == 0
+            || state.quirks.conflict().is_some()
+        {
+            return Err(Chip8Error::BadSnapshot);
+        }
+        self.restore_cpu(state.cpu)?;
+        self.ram = *state.ram;
+        self.display = state.display;
+        self.second_plane = state.second_plane;
+        self.selected_planes = state.selected_planes;
+        self.hires = state.hires;
+        // A builder-set name only stands while its quirks do
+        if state.quirks != self.quirks {
+            self.profile_name = state.quirks.profile_name().to_string();
+        }
+        self.quirks = state.quirks;
+        self.halted = state.halted;
+        self.rpl_flags = state.rpl_flags;
+        self.audio_buffer = state.audio_buffer;
+        self.audio_pitch = state.audio_pitch;
+        self.instruction_count = state.instruction_count;
+        self.rng = Xorshift::from_state(state.rng_state);
+        self.last_draw = None;
+        self.last_collisions = 0;
+        self.mark_all_dirty();
+        self.awaiting_key = false;
+        self.pending_key = None;
+        self.resume_from = None;
+        self.draw_pending = false;
+        self.has_drawn = true;
+        self.clear_fetched();
+        Ok(())
     }
 
-    fn draw_sprite(&mut self, rest: u16) {
-        let init_x = self.v_reg[((rest & 0xF00) >> 8) as usize] % SCREEN_WIDTH as u8;
-        let init_y = self.v_reg[((rest & 0x0F0) >> 4) as usize] % SCREEN_HEIGHT as u8;
+    // Lets a frontend tell "nothing drawn yet" apart from a ROM that draws a black screen
+    pub fn has_drawn(&self) -> bool {
+        self.has_drawn
+    }
 
-        let sprite_height = rest & 0x00F;
-        let sprite_ptr = self.i_reg;
-        self.v_reg[0xF] = 0;
+    // Blanks the screen without touching registers, e.g. for a frontend menu or overlay
+    pub fn clear_display(&mut self) {
+        self.clear_screen();
+    }
+
+    // Mutable view of program RAM for live memory editors. Starts at 0x200 so the
+    // interpreter area with the font can't be clobbered, i.e. index 0 is address 0x200.
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram[START_ADDR as usize..]
+    }
+
+    // Memory access for viewers and patching tools. RAM spans all of the 16-bit address
+    // space so every address is valid. Writes from here don't trigger watchpoints.
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    pub fn write_byte(&mut self, addr: u16, val: u8) {
+        self.check_self_modify(addr);
+        self.ram[addr as usize] = val;
+    }
+
+    // All of RAM from address 0, including the fonts below 0x200
+    pub fn ram_slice(&self) -> &[u8] {
+        &self.ram
+    }
+
+    // True once after each DXYN when the display-wait quirk is on, telling the frontend
+    // to stop cycling until the next frame
+    pub fn take_draw_pending(&mut self) -> bool {
+        std::mem::take(&mut self.draw_pending)
+    }
+
+    pub fn last_draw_rect(&self) -> Option<(u8, u8, u8, u8)> {
+        let scale = self.output_scale() as u8;
+        self.last_draw.map(|(x, y, width, height)| (x * scale, y * scale, width * scale, height * scale))
+    }
+
+    // True when the display changed since the last clear_dirty, so a frontend can skip
+    // redrawing an unchanged screen
+    pub fn display_dirty(&self) -> bool {
+        self.display_dirty
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.display_dirty = false;
+    }
+
+    // How many pixels the last DXYN erased, where VF only says whether any were. Up to
+    // 512, a 16x16 sprite over both planes.
+    pub fn last_draw_collisions(&self) -> u16 {
+        self.last_collisions
+    }
+
+    // Bounding box of every pixel changed since the last call as (x, y, width, height)
+    // in display coordinates, or None if nothing changed. Clears, scrolls and resolution
+    // switches count as the whole screen.
+    pub fn take_dirty_region(&mut self) -> Option<(usize, usize, usize, usize)> {
+        self.dirty
+            .take()
+            .map(|(min_x, min_y, max_x, max_y)| (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    // Same count under the name profiling code tends to look for
+    pub fn cycle_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // True once the program sits in a jump-to-self, the usual way a ROM "ends". It keeps
+    // executing the jump, this only lets a frontend skip the wasted cycles. The jump is
+    // read again from pc, since write_byte or ram_mut may have patched it out since.
+    pub fn is_idle(&self) -> bool {
+        self.idle && self.word_at(self.pc) == 0x1000 | self.pc
+    }
+
+    // True while the program spins on the delay timer with DT still above 0, so nothing
+    // changes until the next timer tick and a frontend can sleep instead of cycling. The
+    // loops recognised are FX07, 3X00, a 1NNN back to the FX07, and FX07, 4X00, the way
+    // out, a 1NNN back.
+    pub fn is_waiting_on_delay_timer(&self) -> bool {
+        self.d_timer > 0 && (0..4).any(|back| self.in_delay_loop(self.pc.wrapping_sub(2 * back), back))
+    }
+
+    // Whether pc, `back` instructions past `head`, is going round a delay loop starting
+    // there rather than about to leave it
+    fn in_delay_loop(&self, head: u16, back: u16) -> bool {
+        let read = self.word_at(head);
+        if read & 0xF0FF != 0xF007 || head > 0x0FFF {
+            return false;
+        }
+        let x = read & 0x0F00;
+        let jump_back = 0x1000 | head;
+        // The compare only goes round again while VX is still nonzero
+        let looping = self.v_reg[(x >> 8) as usize] != 0;
+        let compare = self.word_at(head.wrapping_add(2));
+        if compare == 0x3000 | x && self.word_at(head.wrapping_add(4)) == jump_back {
+            back == 0 || back == 2 || back == 1 && looping
+        } else if compare == 0x4000 | x && self.word_at(head.wrapping_add(6)) == jump_back {
+            back == 0 || back == 3 || back == 1 && looping
+        } else {
+            false
+        }
+    }
+
+    // For frontends that persist high scores between runs
+    pub fn rpl_flags(&self) -> &[u8; NUM_RPL_FLAGS] {
+        &self.rpl_flags
+    }
+
+    pub fn set_rpl_flags(&mut self, flags: [u8; NUM_RPL_FLAGS]) {
+        self.rpl_flags = flags;
+    }
+
+    // Runs up to `cycles` instructions, stopping early if the frame can't make progress
+    pub fn run_frame(&mut self, cycles: usize, keys: &[u8]) -> Result<FrameOutcome, Chip8Error> {
+        for _ in 0..cycles {
+            self.cycle(keys)?;
+            if self.halted {
+                return Ok(FrameOutcome::Halted);
+            }
+            if self.awaiting_key {
+                return Ok(FrameOutcome::AwaitingKey);
+            }
+            if self.take_draw_pending() {
+                return Ok(FrameOutcome::DisplayWait);
+            }
+            if self.is_idle() {
+                return Ok(FrameOutcome::Idle);
+            }
+        }
+        Ok(FrameOutcome::BudgetExhausted)
+    }
+
+    // Runs until pc reaches `target`, returning true with the instruction at `target` not
+    // yet executed, or false if `max_cycles` ran out first
+    pub fn run_until_pc(&mut self, target: u16, max_cycles: usize, keys: &[u8]) -> Result<bool, Chip8Error> {
+        for _ in 0..max_cycles {
+            if self.pc == target {
+                return Ok(true);
+            }
+            self.cycle(keys)?;
+        }
+        Ok(self.pc == target)
+    }
+
+    // TAS/replay entry point: runs exactly `cycles` instructions then `timer_ticks` timer
+    // decrements, with `keys` (indexed by CHIP-8 key) held for the whole frame. Unlike
+    // run_frame it never stops early, so the same inputs always replay identically. That
+    // includes display-wait draws, which don't end the frame and leave nothing pending for
+    // take_draw_pending.
+    pub fn advance_frame(&mut self, keys: [bool; 16], cycles: usize, timer_ticks: u32) -> Result<FrameOutcome, Chip8Error> {
+        let held = (0..16).filter(|&key| keys[key]).fold(0, |mask, key| mask | 1 << key);
+        for _ in 0..cycles {
+            self.cycle_keys(held)?;
+        }
+        self.draw_pending = false;
+        self.tick_timers(timer_ticks);
+
+        Ok(if self.halted {
+            FrameOutcome::Halted
+        } else if self.awaiting_key {
+            FrameOutcome::AwaitingKey
+        } else {
+            FrameOutcome::BudgetExhausted
+        })
+    }
+
+    // Turning the trace off throws away what it recorded
+    pub fn enable_trace(&mut self, enabled: bool) {
+        match (enabled, self.trace.is_some()) {
+            (true, false) => self.trace = Some(Vec::with_capacity(2 * TRACE_LEN)),
+            (false, true) => self.trace = None,
+            _ => {}
+        }
+    }
+
+    // Up to the last TRACE_LEN instructions, oldest first. Errored instructions are
+    // included, with the failing one last.
+    pub fn trace(&self) -> &[TraceEntry] {
+        match &self.trace {
+            Some(trace) => &trace[trace.len().saturating_sub(TRACE_LEN)..],
+            None => &[],
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
 
-        // For every row in sprite
-        for r in 0..sprite_height {
-            let sprite_row = self.ram[(sprite_ptr + r) as usize];
-            let display_row_pos = (init_y + (r as u8)) as usize;
-            if display_row_pos >= SCREEN_HEIGHT {
+    // Watches writes to start..=end by the program itself, not through ram_mut
+    pub fn add_watchpoint(&mut self, start: u16, end: u16) {
+        self.watchpoints.push((start, end));
+    }
+
+    pub fn remove_watchpoint(&mut self, start: u16, end: u16) {
+        self.watchpoints.retain(|&range| range != (start, end));
+    }
+
+    // Debugger version of cycle: stops before executing at a breakpoint, then runs that
+    // instruction on the next call so the program can be continued. Watchpoints are
+    // reported after the writing instruction has run.
+    pub fn cycle_checked(&mut self, keys: &[u8]) -> Result<CycleOutcome, Chip8Error> {
+        if self.resume_from.take() != Some(self.pc) && self.breakpoints.contains(&self.pc) {
+            self.resume_from = Some(self.pc);
+            return Ok(CycleOutcome::BreakpointHit(self.pc));
+        }
+        self.watch_hit = None;
+        self.cycle(keys)?;
+        Ok(match self.watch_hit.take() {
+            Some((addr, old, new)) => CycleOutcome::WatchpointHit { addr, old, new },
+            None => CycleOutcome::Executed,
+        })
+    }
+
+    // Executes exactly one instruction, stepping over any breakpoint at pc
+    pub fn step(&mut self, keys: &[u8]) -> Result<(), Chip8Error> {
+        self.cycle(keys)
+    }
+
+    // Runs up to `n` instructions through cycle_checked, returning early on an error, a
+    // breakpoint or watchpoint, or once the program halts (check is_halted). Unlike
+    // run_frame it keeps going through FX0A waits and display-wait draws.
+    pub fn run_cycles(&mut self, n: usize, keys: &[u8]) -> Result<CycleOutcome, Chip8Error> {
+        for _ in 0..n {
+            if self.halted {
                 break;
             }
-            // for every bit in sprite row (byte)
-            for c in 0..8 {
-                // Shifting mask to extract only the specific pixel of the sprite we are on
-                // Then check to see if it's not 0 at the masked bit
-                let sprite_pixel = sprite_row & (0b10000000 >> c) != 0;
-                let display_col_pos = (init_x + c) as usize;
-                // If it reaches right edge of screen, stop row
-                if display_col_pos >= SCREEN_WIDTH {
-                    break;
+            let outcome = self.cycle_checked(keys)?;
+            if outcome != CycleOutcome::Executed {
+                return Ok(outcome);
+            }
+        }
+        Ok(CycleOutcome::Executed)
+    }
+
+    // On error pc has already moved past the offending instruction. Once halted by 00FD
+    // this does nothing, pc stays put and the instruction count stops.
+    pub fn cycle(&mut self, keys: &[u8]) -> Result<(), Chip8Error> {
+        self.cycle_keys(key_mask(keys))
+    }
+
+    // Like cycle, with bit N of `keys` set while key N is held
+    pub fn cycle_keys(&mut self, keys: u16) -> Result<(), Chip8Error> {
+        if self.halted {
+            return Ok(());
+        }
+        self.awaiting_key = false;
+        // Whatever runs, pc is off the reported breakpoint, so reaching it again stops
+        self.resume_from = None;
+        let pc = self.pc;
+        let instruction = self.fetch();
+        if let Some(trace) = self.trace.as_mut() {
+            if trace.len() == 2 * TRACE_LEN {
+                trace.drain(..TRACE_LEN);
+            }
+            trace.push(TraceEntry { pc, opcode: instruction });
+        }
+        self.decode_and_exec(instruction, keys)?;
+        self.instruction_count += 1;
+        Ok(())
+    }
+
+    fn fetch(&mut self) -> u16 {
+        if let Some(fetched) = self.fetched.as_mut() {
+            for addr in [self.pc, self.pc.wrapping_add(1)] {
+                fetched[addr as usize / 64] |= 1 << (addr % 64);
+            }
+        }
+        // Each instruction takes two bytes
+        let mut high_byte: u16 = self.ram[self.pc as usize].into();
+        let low_byte: u16 = self.ram[self.pc.wrapping_add(1) as usize].into();
+        
+        // Increment pc here to avoid having to do this later
+        self.pc = self.pc.wrapping_add(2);
+        high_byte <<= 8;
+        high_byte + low_byte
+    }
+
+    fn decode_and_exec(&mut self, instruction: u16, keys: u16) -> Result<(), Chip8Error> {
+        // 1st 4 bit "nibble"
+        let nibble = (instruction & 0xF000) >> 12;
+        let rest = instruction & 0x0FFF;
+        match nibble {
+            // Some instructions out of order to group similar opcodes
+            0x0 => match rest {
+                    0x000 => self.noop(),
+                    0x0C0..=0x0CF => self.scroll(0, (rest & 0x00F) as isize),
+                    0x0D0..=0x0DF => self.scroll(0, -((rest & 0x00F) as isize)),
+                    0x0E0 => self.clear_screen(),
+                    0x0EE => self.ret_subroutine()?,
+                    0x0FB => self.scroll(self.horizontal_scroll(), 0),
+                    0x0FC => self.scroll(-self.horizontal_scroll(), 0),
+                    0x0FD => self.halted = true,
+                    0x0FE => self.set_hires(false),
+                    0x0FF => self.set_hires(true),
+
+                    _ => return Err(Chip8Error::InvalidOpcode(instruction)),
+                },
+            0x1 => self.jump(rest),
+            0x2 => self.call(rest)?,
+            0x3 => self.skip_eq_val(rest),
+            0x4 => self.skip_neq_val(rest),
+            0x5 => self.skip_eq_reg(rest),
+            0x9 => self.skip_neq_reg(rest),
+            0x6 => self.set_v(rest),
+            0x7 => self.add_v_no_carry(rest),
+            // Logical and arithmetic instructions determined by last nibble
+            0x8 => match rest & 0x00F {
+                0x0 => self.set(rest),
+                0x1 => self.or(rest),
+                0x2 => self.and(rest),
+                0x3 => self.xor(rest),
+                0x4 => self.add(rest),
+                //sub function handles which to exec
+                0x5 => self.sub(rest), //sub x-y
+                0x7 => self.sub(rest), //sub y-x
+                0x6 => self.right_shift(rest),
+                0xE => self.left_shift(rest),
+                _ => return Err(Chip8Error::InvalidOpcode(instruction)),
+            }
+            0xA => self.set_i(rest),
+            0xB => self.jump_offset(rest),
+            0xC => self.rand(rest),
+            0xD => self.draw_sprite(rest),
+            0xE => match rest & 0x0FF {
+                //skip if key
+                0x9E | 0xA1 => self.key_skip(rest, keys),
+                _ => return Err(Chip8Error::InvalidOpcode(instruction)),
+            },
+            0xF => match rest & 0x0FF {
+                // F000 NNNN, rest is 0x000 so X is 0 and this can't clash with FX01
+                0x00 if rest == 0x000 && self.quirks.xo_chip => self.load_i_long(),
+                0x01 if self.quirks.xo_chip => self.select_planes(rest),
+                0x02 if self.quirks.xo_chip => self.load_audio_buffer(),
+                0x3A if self.quirks.xo_chip => self.audio_pitch = self.v_reg[((rest & 0xF00) >> 8) as usize],
+                //timers
+                0x07 => self.v_reg[((rest & 0xF00) >> 8) as usize] = self.d_timer,
+                0x15 => self.d_timer = self.v_reg[((rest & 0xF00) >> 8) as usize],
+                0x18 => self.set_sound_timer(rest),
+                
+                0x0A => self.await_key(rest, keys),
+                0x1E => self.add_i(rest),
+                0x29 => self.set_i_font(rest),
+                0x30 => self.set_i_big_font(rest),
+                0x33 => self.bcd(rest),
+                0x55 => self.store_mem(rest),
+                0x65 => self.fill_mem(rest),
+                0x75 => self.store_rpl_flags(rest),
+                0x85 => self.load_rpl_flags(rest),
+                _ => return Err(Chip8Error::InvalidOpcode(instruction)),
+            },
+
+            _ => return Err(Chip8Error::InvalidOpcode(instruction)),
+        };
+        Ok(())
+    }
+
+    //TODO ensure ordering of instructions based on opcode sheet
+
+    fn noop(&self) {}
+    
+    // Only blanks the selected planes
+    fn clear_screen(&mut self) {
+        for plane in 0..NUM_PLANES {
+            if self.selected_planes & (1 << plane) != 0 {
+                let pixels = self.plane_mut(plane);
+                let cleared = pixels.iter().any(|&row| row != 0);
+                *pixels = [0; HIRES_HEIGHT];
+                self.has_drawn |= cleared;
+                if cleared {
+                    self.mark_all_dirty();
                 }
-                let display_index = SCREEN_WIDTH * display_row_pos + display_col_pos;
-                let display_pixel = &mut self.display[display_index];
-                // If both sprite and pixel are on, turn off pixel and set VF to 1
-                if sprite_pixel &&  *display_pixel {
-                    *display_pixel = false;
-                    self.v_reg[0xF] = 1;
-                } else if sprite_pixel && !*display_pixel {
-                    // If sprite is on and pixel is off, turn on pixel
-                    *display_pixel = true;
+            }
+        }
+    }
+
+    // The dirty region is in display coordinates, the whole 128x64 under always-hires
+    fn mark_all_dirty(&mut self) {
+        self.display_dirty = true;
+        let (width, height) = self.display_dimensions();
+        grow_region(&mut self.dirty, (0, 0, width - 1, height - 1));
+    }
+
+    // Switching resolution also clears the screen, since the pixel layout changes. The
+    // last sprite went with it, and its coordinates would be off in the new resolution.
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.display = [0; HIRES_HEIGHT];
+        self.second_plane = [0; HIRES_HEIGHT];
+        self.last_draw = None;
+        self.mark_all_dirty();
+    }
+
+    fn plane_mut(&mut self, plane: usize) -> &mut Plane {
+        if plane == 0 { &mut self.display } else { &mut self.second_plane }
+    }
+
+    // FN01, N is the plane bitmask
+    fn select_planes(&mut self, rest: u16) {
+        self.selected_planes = ((rest & 0xF00) >> 8) as u8 & 0b11;
+    }
+
+    // SUPER-CHIP scrolls 4 pixels sideways in hires, and half that in lores like the
+    // original HP48 interpreter, which scrolled by hires pixels in either mode
+    fn horizontal_scroll(&self) -> isize {
+        if self.hires { 4 } else { 2 }
+    }
+
+    // Shifts the active display area of the selected planes by dx columns and dy rows
+    // (positive is right and down), clearing whatever gets uncovered
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = self.native_dimensions();
+        // keeps lores rows from growing past the left edge into the unused high bits
+        let row_mask = u128::MAX >> (HIRES_WIDTH - width);
+        for plane in 0..NUM_PLANES {
+            if self.selected_planes & (1 << plane) == 0 {
+                continue;
+            }
+            let pixels = self.plane_mut(plane);
+            let mut scrolled = [0; HIRES_HEIGHT];
+            for (y, row) in scrolled.iter_mut().enumerate().take(height) {
+                let src_y = y as isize - dy;
+                if (0..height as isize).contains(&src_y) {
+                    // Bit x is column x, so moving right shifts towards the high bits
+                    let src = pixels[src_y as usize];
+                    let shifted = if dx >= 0 { src << dx } else { src >> -dx };
+                    *row = shifted & row_mask;
                 }
             }
+            let moved = pixels.iter().any(|&row| row != 0);
+            *pixels = scrolled;
+            self.has_drawn |= moved;
+            if moved {
+                self.mark_all_dirty();
+            }
         }
     }
 
-    fn key_skip(&mut self, rest: u16, keys: &[u8]) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let key_pressed = keys.contains(&self.v_reg[x]);
-        
+    fn jump(&mut self, addr: u16) {
+        // pc is already past the jump, so its own address is 2 back
+        self.idle = addr == self.pc.wrapping_sub(2);
+        self.pc = addr;
+    }
 
-        if rest & 0x0FF == 0x9E {
-            if key_pressed { self.pc += 2 };
-        } else if rest & 0x0FF == 0xA1 {
-            if !key_pressed { self.pc += 2 }
+    fn call(&mut self, addr: u16) -> Result<(), Chip8Error> {
+        if self.sp == MAX_STACK {
+            return Err(Chip8Error::StackOverflow);
         }
+        self.stack[self.sp] = self.pc;
+        self.sp += 1;
+        self.pc = addr;
+        Ok(())
     }
 
-    fn await_key(&mut self, rest: u16, keys: &[u8]) {
-        let x = ((rest & 0xF00) >> 8) as usize;
-        let key_pressed = !keys.is_empty();
+    fn ret_subroutine(&mut self) -> Result<(), Chip8Error> {
+        if self.sp == 0 {
+            return Err(Chip8Error::StackUnderflow);
+        }
+        self.sp -= 1;
+        self.pc = self.stack[self.sp];
+        Ok(())
+    }
+    
+    fn skip_eq_val(&mut self, rest: u16) {
+        let x= ((rest & 0xF00) >> 8) as usize;
+        let val: u8 = (rest & 0x0FF) as u8;
 
-        if key_pressed { 
-            self.v_reg[x] = keys[0];
-        } else {
-            self.pc -= 2;
+        if self.v_reg[x] == val {
+            self.skip_next();
         }
     }
 
-    fn set_i_font(&mut self, rest: u16) {
+    fn skip_neq_val(&mut self, rest: u16) {
+        let x= ((rest & 0xF00) >> 8) as usize;
+        let val: u8 = (rest & 0x0FF) as u8;
+
+        if self.v_reg[x] != val {
+            self.skip_next();
+        }
+    }
+
+    fn skip_eq_reg(&mut self, rest: u16) {
         let x = ((rest & 0xF00) >> 8) as usize;
-        let addr = 0x50 + (self.v_reg[x] * 5);
-        self.i_reg = addr as u16;
+        let y = ((rest & 0x0F0) >> 4) as usize;
+
+        if self.v_reg[x] == self.v_reg[y] {
+            self.skip_next();
+        }
     }
 
-    // Binary-coded decimal conversion
-    fn bcd(&mut self, rest: u16) {
-        let mut x = self.v_reg[((rest & 0xF00) >> 8) as usize];
-        let mut digits = [0u8; 3];
+    fn skip_neq_reg(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let y = ((rest & 0x0F0) >> 4) as usize;
 
-        // Extract all 3 digits and store them individually in array
-        for i in (0..3).rev() {
-            digits[i] = x % 10;
-            x /= 10;
+        if self.v_reg[x] != self.v_reg[y] {
+            self.skip_next();
         }
-        //store digits in memory at i
-        let i: usize = self.i_reg as usize;
-        self.ram[i..i+3].copy_from_slice(&digits);
     }
 
-    fn store_mem(&mut self, rest: u16) {
+    fn set_v(&mut self, rest: u16) {
         let x = ((rest & 0xF00) >> 8) as usize;
-        let i: usize = self.i_reg as usize;
-        self.ram[i..=i+x].copy_from_slice(&self.v_reg[0..=x]);
+        self.v_reg[x] = (rest & 0xFF) as u8;
     }
 
-    fn fill_mem(&mut self, rest: u16) {
+    fn add_v_no_carry(&mut self, rest: u16){
+        let x = ((rest & 0xF00) >> 8) as usize;
+        self.v_reg[x] = self.v_reg[x].wrapping_add((rest & 0xFF) as u8);
+    }
+
+    fn set(&mut self, rest: u16) {
         let x = ((rest & 0xF00) >> 8) as usize;
-        let i: usize = self.i_reg as usize;
-        self.v_reg[0..=x].copy_from_slice(& self.ram[i..=i+x]);
+        let y = ((rest & 0x0F0) >> 4) as usize;
+        self.v_reg[x] = self.v_reg[y];
     }
-}
\ No newline at end of file
+
+    fn or(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let y = ((rest & 0x0F0) >> 4) as usize;
+        self.v_reg[x] |= self.v_reg[y];
+        self.reset_vf_after_logic();
+    }
+
+    fn and(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let y = ((rest & 0x0F0) >> 4) as usize;
+        self.v_reg[x] &= self.v_reg[y];
+        self.reset_vf_after_logic();
+    }
+
+    fn xor(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let y = ((rest & 0x0F0) >> 4) as usize;
+        self.v_reg[x] ^= self.v_reg[y];
+        self.reset_vf_after_logic();
+    }
+
+    // Done after the logic op itself so VF still ends up 0 when X is F
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.v_reg[0xF] = 0;
+        }
+    }
+
+    fn add(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let y = ((rest & 0x0F0) >> 4) as usize;
+        let sum = self.v_reg[x] as u16 + self.v_reg[y] as u16;
+
+        self.v_reg[0xF] = if sum < 256 {0} else {1};
+        self.v_reg[x] = (sum & 0x00FF) as u8;
+    }
+
+    fn sub(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let y = ((rest & 0x0F0) >> 4) as usize;
+
+        let minuend = if (rest & 0x00F) == 5 { x } else { y };
+        let subtrahend = if (rest & 0x00F) == 5 { y } else { x };
+
+        // set VF to 1 if there's no borrow, i.e. left > right
+        self.v_reg[0xF] = if self.v_reg[minuend] >= self.v_reg[subtrahend] { 1 } else { 0 };
+        self.v_reg[x] = self.v_reg[minuend].wrapping_sub(self.v_reg[subtrahend]);
+    }
+
+    fn right_shift(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        if self.quirks.shift_uses_vy {
+            let y: usize = ((rest & 0x0F0) >> 4) as usize;
+            self.v_reg[x] = self.v_reg[y];
+        }
+        // extract lsb
+        self.v_reg[0xF] = self.v_reg[x] & 1;
+        self.v_reg[x] >>= 1;
+    }
+
+    fn left_shift(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        if self.quirks.shift_uses_vy {
+            let y: usize = ((rest & 0x0F0) >> 4) as usize;
+            self.v_reg[x] = self.v_reg[y];
+        }
+        // extract msb
+        self.v_reg[0xF] = self.v_reg[x] >> 7;
+        self.v_reg[x] <<= 1;
+    }
+
+    fn set_i(&mut self, addr: u16) {
+        self.write_i(addr);
+    }
+
+    // All writes to I go through here so the 12-bit mask is applied consistently
+    fn write_i(&mut self, val: u16) {
+        self.i_reg = if self.quirks.i_mask_12bit { val & 0x0FFF } else { val };
+    }
+
+    fn add_i(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        self.write_i(self.i_reg.wrapping_add(self.v_reg[x] as u16));
+    }
+
+    // BNNN jumps to NNN + V0; with the quirk it's read as BXNN and jumps to XNN + VX,
+    // where X is the high nibble of the address
+    fn jump_offset(&mut self, rest: u16) {
+        let offset_reg = if self.quirks.jump_offset_uses_vx { ((rest & 0xF00) >> 8) as usize } else { 0 };
+        self.pc = (self.v_reg[offset_reg] as u16) + rest;
+    }
+
+    fn rand(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let rand = self.rng.next_byte();
+        self.v_reg[x] = rand & ((rest & 0x0FF) as u8);
+    }
+
+    fn draw_sprite(&mut self, rest: u16) {
+        let (width, height) = self.native_dimensions();
+        let scale = self.output_scale();
+        let init_x = (self.v_reg[((rest & 0xF00) >> 8) as usize] as usize % width) as u8;
+        let init_y = (self.v_reg[((rest & 0x0F0) >> 4) as usize] as usize % height) as u8;
+
+        // SUPER-CHIP's DXY0 in hires draws 16x16, two bytes per row
+        let (sprite_width, sprite_height) = match rest & 0x00F {
+            0 if self.hires => (16, 16),
+            n => (8, n),
+        };
+        let row_bytes = sprite_width as u16 / 8;
+        self.v_reg[0xF] = 0;
+        self.last_draw = Some((init_x, init_y, sprite_width as u8, sprite_height as u8));
+        self.has_drawn = true;
+        self.draw_pending = self.quirks.display_wait;
+
+        // XO-CHIP draws into each selected plane in turn, each one taking the next
+        // chunk of sprite data
+        let mut sprite_ptr = self.i_reg;
+        let mut collisions = 0u16;
+        for plane in 0..NUM_PLANES {
+            if self.selected_planes & (1 << plane) == 0 {
+                continue;
+            }
+            let pixels = if plane == 0 { &mut self.display } else { &mut self.second_plane };
+            // For every row in sprite
+            for r in 0..sprite_height {
+                let row_addr = sprite_ptr.wrapping_add(r * row_bytes);
+                let sprite_row = (0..row_bytes)
+                    .fold(0u16, |row, b| row << 8 | self.ram[row_addr.wrapping_add(b) as usize] as u16);
+                let mut display_row_pos = init_y as usize + r as usize;
+                if display_row_pos >= height {
+                    // Either wrap to the top or stop drawing at the bottom edge
+                    if !self.quirks.sprite_wrapping {
+                        break;
+                    }
+                    display_row_pos %= height;
+                }
+                // for every bit in sprite row (one byte, or two for 16 wide)
+                for c in 0..sprite_width {
+                    // Shifting mask to extract only the specific pixel of the sprite we are on
+                    // Then check to see if it's not 0 at the masked bit
+                    let sprite_pixel = sprite_row & (1 << (sprite_width - 1 - c)) != 0;
+                    let mut display_col_pos = init_x as usize + c;
+                    // If it reaches right edge of screen, wrap to the left or stop the row
+                    if display_col_pos >= width {
+                        if !self.quirks.sprite_wrapping {
+                            break;
+                        }
+                        display_col_pos %= width;
+                    }
+                    if sprite_pixel {
+                        let (x, y) = (display_col_pos, display_row_pos);
+                        // Sprite pixels flip the display pixel, turning one off sets VF to 1
+                        if toggle_pixel(pixels, x, y) {
+                            self.v_reg[0xF] = 1;
+                            collisions += 1;
+                        }
+                        grow_region(&mut self.dirty, (x * scale, y * scale, x * scale + scale - 1, y * scale + scale - 1));
+                        self.display_dirty = true;
+                    }
+                }
+            }
+            sprite_ptr = sprite_ptr.wrapping_add(sprite_height * row_bytes);
+        }
+        self.last_collisions = collisions;
+    }
+
+    fn key_skip(&mut self, rest: u16, keys: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let key = if self.mask_key_index { self.v_reg[x] & 0xF } else { self.v_reg[x] };
+        // Unmasked, VX above F names no key so it's never pressed
+        let key_pressed = key < 16 && keys & (1 << key) != 0;
+
+        if rest & 0x0FF == 0x9E {
+            if key_pressed { self.skip_next() };
+        } else if rest & 0x0FF == 0xA1 && !key_pressed {
+            self.skip_next();
+        }
+    }
+
+    // Like the COSMAC VIP, FX0A waits for a key to be pressed and then released, so a
+    // held key doesn't get read again by the next FX0A
+    fn await_key(&mut self, rest: u16, keys: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+
+        match self.pending_key {
+            Some(key) if keys & (1 << key) == 0 => {
+                self.v_reg[x] = key;
+                self.pending_key = None;
+            }
+            pending => {
+                // With several down at once the lowest numbered key wins
+                if pending.is_none() && keys != 0 {
+                    self.pending_key = Some(keys.trailing_zeros() as u8);
+                }
+                self.pc = self.pc.wrapping_sub(2);
+                self.awaiting_key = true;
+            }
+        }
+    }
+
+    fn set_sound_timer(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let ticks = self.v_reg[x];
+        // A write of 0 still silences the beep
+        self.write_sound_timer(if ticks > 0 { ticks.max(self.min_sound_ticks) } else { 0 });
+    }
+
+    // Every sound timer write goes through here so the callback sees each transition
+    fn write_sound_timer(&mut self, ticks: u8) {
+        let was_active = self.is_sound_active();
+        self.s_timer = ticks;
+        let active = self.is_sound_active();
+        if active != was_active {
+            if let Some(callback) = self.sound_callback.as_mut() {
+                callback(active);
+            }
+        }
+    }
+
+    // Only the low nibble picks a digit, the font stops at F
+    fn set_i_font(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let addr = 0x50 + (self.v_reg[x] & 0xF) as u16 * 5;
+        self.write_i(addr);
+    }
+
+    // Big font digits are 10 bytes each and there's only 0-9
+    fn set_i_big_font(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        let addr = 0xA0 + (self.v_reg[x] as u16 % 10) * 10;
+        self.write_i(addr);
+    }
+
+    // Binary-coded decimal conversion
+    fn bcd(&mut self, rest: u16) {
+        let mut x = self.v_reg[((rest & 0xF00) >> 8) as usize];
+        let mut digits = [0u8; 3];
+
+        // Extract all 3 digits and store them individually in array
+        for i in (0..3).rev() {
+            digits[i] = x % 10;
+            x /= 10;
+        }
+        //store digits in memory at i
+        for (offset, digit) in digits.into_iter().enumerate() {
+            self.write_ram(self.i_reg, offset, digit);
+        }
+    }
+
+    fn store_mem(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        for offset in 0..=x {
+            self.write_ram(self.i_reg, offset, self.v_reg[offset]);
+        }
+        self.increment_i_after_load_store(x);
+    }
+
+    fn fill_mem(&mut self, rest: u16) {
+        let x = ((rest & 0xF00) >> 8) as usize;
+        for offset in 0..=x {
+            self.v_reg[offset] = self.read_ram(self.i_reg, offset);
+        }
+        self.increment_i_after_load_store(x);
+    }
+
+    // RAM spans the whole 16-bit address space, so base + offset wraps around past 0xFFFF
+    // like the address lines would instead of indexing off the end
+    fn word_at(&self, addr: u16) -> u16 {
+        u16::from_be_bytes([self.read_ram(addr, 0), self.read_ram(addr, 1)])
+    }
+
+    fn read_ram(&self, base: u16, offset: usize) -> u8 {
+        self.ram[base.wrapping_add(offset as u16) as usize]
+    }
+
+    fn write_ram(&mut self, base: u16, offset: usize, value: u8) {
+        let addr = base.wrapping_add(offset as u16);
+        let watched = self.watchpoints.iter().any(|&(start, end)| (start..=end).contains(&addr));
+        if watched && self.watch_hit.is_none() {
+            self.watch_hit = Some((addr, self.ram[addr as usize], value));
+        }
+        self.check_self_modify(addr);
+        self.ram[addr as usize] = value;
+    }
+
+    fn check_self_modify(&mut self, addr: u16) {
+        let Some(fetched) = self.fetched.as_ref() else {
+            return;
+        };
+        if fetched[addr as usize / 64] & 1 << (addr % 64) != 0 {
+            if let Some(callback) = self.self_modify_callback.as_mut() {
+                callback(addr);
+            }
+        }
+    }
+
+    fn clear_fetched(&mut self) {
+        if let Some(fetched) = self.fetched.as_mut() {
+            fetched.fill(0);
+        }
+    }
+
+    // Reads the second word of F000 NNNN as the address, then moves pc past it
+    fn load_i_long(&mut self) {
+        let addr = self.fetch();
+        self.write_i(addr);
+    }
+
+    // XO-CHIP skips step over the whole of a following F000 NNNN, not just its first word.
+    // Without xo_chip F000 is just an invalid opcode and takes two bytes like the rest.
+    fn skip_next(&mut self) {
+        let next = u16::from_be_bytes([self.read_ram(self.pc, 0), self.read_ram(self.pc, 1)]);
+        self.pc = self.pc.wrapping_add(if next == 0xF000 && self.quirks.xo_chip { 4 } else { 2 });
+    }
+
+    fn load_audio_buffer(&mut self) {
+        for offset in 0..AUDIO_BUFFER_SIZE {
+            self.audio_buffer[offset] = self.read_ram(self.i_reg, offset);
+        }
+    }
+
+    // X past 7 is clamped, there are only 8 flags
+    fn store_rpl_flags(&mut self, rest: u16) {
+        let x = (((rest & 0xF00) >> 8) as usize).min(NUM_RPL_FLAGS - 1);
+        self.rpl_flags[0..=x].copy_from_slice(&self.v_reg[0..=x]);
+    }
+
+    fn load_rpl_flags(&mut self, rest: u16) {
+        let x = (((rest & 0xF00) >> 8) as usize).min(NUM_RPL_FLAGS - 1);
+        self.v_reg[0..=x].copy_from_slice(&self.rpl_flags[0..=x]);
+    }
+
+    // The COSMAC VIP stepped I through memory as it went, leaving it at I + X + 1
+    fn increment_i_after_load_store(&mut self, x: usize) {
+        if self.quirks.load_store_increments_i {
+            self.write_i(self.i_reg.wrapping_add(x as u16 + 1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::*;
+
+    fn core_with(program: &[u16]) -> Core {
+        Core::new(&to_bytes(program), false)
+    }
+
+    fn core_with_quirks(program: &[u16], quirks: Quirks) -> Core {
+        CoreBuilder::new().program(&to_bytes(program)).quirks(quirks).build().unwrap()
+    }
+
+    fn run(core: &mut Core, cycles: usize) {
+        for _ in 0..cycles {
+            core.cycle(&[]).unwrap();
+        }
+    }
+
+    #[test]
+    fn min_sound_ticks_bumps_short_writes() {
+        let program = [ld_vx_byte(0, 1), ld_st_vx(0), ld_vx_byte(0, 0), ld_st_vx(0)];
+        let mut core = core_with(&program);
+        run(&mut core, 2);
+        assert_eq!(core.sound_timer(), 1);
+
+        let mut core = CoreBuilder::new().program(&to_bytes(&program)).min_sound_ticks(2).build().unwrap();
+        run(&mut core, 2);
+        assert_eq!(core.sound_timer(), 2);
+        // A write of 0 still silences it
+        run(&mut core, 2);
+        assert_eq!(core.sound_timer(), 0);
+    }
+
+    // Raw text of a top-level field in state_json's output, which has no nested objects
+    fn json_field<'a>(json: &'a str, name: &str) -> &'a str {
+        let start = json.find(&format!("\"{}\":", name)).unwrap() + name.len() + 3;
+        let rest = &json[start..];
+        let end = if rest.starts_with('[') { rest.find(']').unwrap() + 1 } else { rest.find([',', '}']).unwrap() };
+        &rest[..end]
+    }
+
+    #[test]
+    fn state_json_round_trips_key_fields() {
+        let mut core = core_with(&[ld_vx_byte(3, 0x2A), ld_i(0x345), call(0x208), 0, add_vx_byte(1, 1)]);
+        core.write_byte(0xFFFF, 0xAB);
+        run(&mut core, 4);
+        let json = core.state_json();
+        assert!(json.starts_with('{') && json.ends_with('}'));
+
+        assert_eq!(json_field(&json, "pc").parse::<u16>().unwrap(), core.pc());
+        assert_eq!(json_field(&json, "i").parse::<u16>().unwrap(), 0x345);
+        let v: Vec<u8> = json_field(&json, "v").trim_matches(['[', ']']).split(',').map(|r| r.parse().unwrap()).collect();
+        assert_eq!(v, core.registers());
+        assert_eq!(json_field(&json, "stack"), "[518]");
+        assert_eq!(json_field(&json, "delay_timer"), "0");
+
+        let ram_hex = json_field(&json, "ram").trim_matches('"');
+        let ram: Vec<u8> = (0..ram_hex.len()).step_by(2)
+            .map(|at| u8::from_str_radix(&ram_hex[at..at + 2], 16).unwrap())
+            .collect();
+        assert_eq!(ram, core.ram_slice());
+    }
+
+    // Stand-in for the keypad test ROM, which can't be fetched here. With 3 and 7 held it
+    // checks EX9E skips on a held key, EXA1 skips on a released one and that FX0A takes
+    // the lowest held key only once everything is let go. Draws a 1 on success, a 0 if
+    // any check fails.
+    const KEYPAD_FIXTURE: [u16; 16] = [
+        0x6003, // 200 LD V0, 3
+        0xE09E, // 202 SKP V0
+        0x1216, // 204 JP fail
+        0x6105, // 206 LD V1, 5
+        0xE1A1, // 208 SKNP V1
+        0x1216, // 20A JP fail
+        0xF20A, // 20C LD V2, K
+        0x3203, // 20E SE V2, 3
+        0x1216, // 210 JP fail
+        0x6301, // 212 LD V3, 1
+        0x1218, // 214 JP draw
+        0x6300, // 216 fail: LD V3, 0
+        0xF329, // 218 draw: LD F, V3
+        0x6400, // 21A LD V4, 0
+        0xD445, // 21C DRW V4, V4, 5
+        0x121E, // 21E JP 21E
+    ];
+
+    fn glyph_screen(digit: u8) -> Plane {
+        let mut core = core_with(&[ld_vx_byte(0, digit), ld_f_vx(0), ld_vx_byte(1, 0), drw(1, 1, 5)]);
+        run(&mut core, 4);
+        core.display
+    }
+
+    #[test]
+    fn keypad_fixture_passes() {
+        let mut core = core_with(&KEYPAD_FIXTURE);
+        let held = 1 << 0x3 | 1 << 0x7;
+        for _ in 0..10 {
+            core.cycle_keys(held).unwrap();
+        }
+        // Parked on FX0A for as long as the keys stay down
+        assert_eq!(core.pc(), 0x20C);
+        for _ in 0..10 {
+            core.cycle_keys(0).unwrap();
+        }
+        assert_eq!(core.registers()[2], 0x3);
+        assert_eq!(core.display, glyph_screen(1));
+    }
+
+    #[test]
+    fn keypad_fixture_fails_without_keys() {
+        let mut core = core_with(&KEYPAD_FIXTURE);
+        for _ in 0..10 {
+            core.cycle_keys(0).unwrap();
+        }
+        assert_eq!(core.display, glyph_screen(0));
+        assert_ne!(glyph_screen(1), glyph_screen(0));
+    }
+
+    #[test]
+    fn i_mask_12bit_wraps_i_writes() {
+        let program = to_bytes(&[ld_i(0xFFF), ld_vx_byte(0, 2), add_i_vx(0)]);
+        let mut core = Core::new(&program, false);
+        run(&mut core, 3);
+        assert_eq!(core.i(), 0x1001);
+
+        let quirks = Quirks { i_mask_12bit: true, xo_chip: false, ..Quirks::default() };
+        let mut core = CoreBuilder::new().program(&program).quirks(quirks).build().unwrap();
+        run(&mut core, 3);
+        assert_eq!(core.i(), 0x001);
+    }
+
+    #[test]
+    fn presets_validate() {
+        assert_eq!(Quirks::default().validate(), Ok(()));
+        assert_eq!(Quirks::cosmac_vip().validate(), Ok(()));
+        let vip_masked = Quirks { i_mask_12bit: true, ..Quirks::cosmac_vip() };
+        assert_eq!(vip_masked.validate(), Ok(()));
+    }
+
+    #[test]
+    fn masked_i_with_xo_chip_is_rejected() {
+        let quirks = Quirks { i_mask_12bit: true, ..Quirks::default() };
+        assert!(quirks.validate().unwrap_err().contains("xo_chip"));
+
+        // F000 1234 would otherwise load I as 0x234
+        let program = to_bytes(&[0xF000, 0x1234]);
+        let built = CoreBuilder::new().program(&program).i_mask_12bit(true).build();
+        assert!(matches!(built, Err(Chip8Error::IncompatibleQuirks(_))));
+    }
+
+    #[test]
+    fn xo_chip_opcodes_need_the_quirk() {
+        let program = to_bytes(&[0xF000, 0x1234]);
+        let mut core = Core::new(&program, false);
+        run(&mut core, 1);
+        assert_eq!(core.i(), 0x1234);
+
+        let mut core = Core::with_quirks(&program, Quirks::cosmac_vip());
+        assert_eq!(core.cycle(&[]), Err(Chip8Error::InvalidOpcode(0xF000)));
+    }
+
+    #[test]
+    fn assembled_program_draws() {
+        let source = "LD V0, 3\nLD F, V0\nLD V1, 10\nDRW V1, V1, 5\nspin: JP spin";
+        let mut core = Core::from_asm(source).unwrap();
+        run(&mut core, 4);
+        // Top row of the 3 glyph is 0xF0
+        assert!(core.pixel(10, 10) && core.pixel(13, 10) && !core.pixel(14, 10));
+
+        assert!(core.load_asm("LD V0, 0x100").is_err());
+        assert!(core.pixel(10, 10));
+        core.load_asm("CLS").unwrap();
+        assert!(!core.pixel(10, 10));
+    }
+
+    #[test]
+    fn save_cpu_round_trips() {
+        let mut core = core_with(&[ld_vx_byte(5, 0x55), ld_i(0x321), ld_dt_vx(5), call(0x20A), 0, add_vx_byte(5, 1)]);
+        run(&mut core, 5);
+        let saved = core.save_cpu();
+        assert_eq!((saved.pc, saved.i_reg, saved.d_timer, saved.sp), (0x20C, 0x321, 0x55, 1));
+
+        core.reset();
+        assert_ne!(core.save_cpu(), saved);
+        core.restore_cpu(saved.clone()).unwrap();
+        assert_eq!(core.save_cpu(), saved);
+        assert_eq!(core.registers()[5], 0x56);
+    }
+
+    #[test]
+    fn has_drawn_flips_on_first_draw() {
+        // Clearing an already blank screen doesn't count
+        let mut core = core_with(&[cls(), ld_i(0x50), drw(0, 0, 5)]);
+        run(&mut core, 2);
+        assert!(!core.has_drawn());
+        run(&mut core, 1);
+        assert!(core.has_drawn());
+        core.reset();
+        assert!(!core.has_drawn());
+    }
+
+    #[test]
+    fn key_skip_masking() {
+        // SKP V0 then SKNP V0, each skipping a LD V1 that marks it didn't skip
+        let program = to_bytes(&[skp(0), ld_vx_byte(1, 1), sknp(0), ld_vx_byte(2, 1)]);
+        let skips = |v0: u8, keys: u16, masked: bool| {
+            let mut core = CoreBuilder::new().program(&program).mask_key_index(masked).build().unwrap();
+            core.v_reg[0] = v0;
+            while core.pc() < 0x208 {
+                core.cycle_keys(keys).unwrap();
+            }
+            (core.registers()[1] == 0, core.registers()[2] == 0)
+        };
+        let key_0 = 1 << 0x0;
+
+        // In range both settings agree
+        for masked in [false, true] {
+            assert_eq!(skips(0x0, key_0, masked), (true, false));
+            assert_eq!(skips(0x0, 0, masked), (false, true));
+        }
+        // 0x20 is never pressed unmasked, and is key 0 masked
+        assert_eq!(skips(0x20, key_0, false), (false, true));
+        assert_eq!(skips(0x20, key_0, true), (true, false));
+        assert_eq!(skips(0x20, 0, true), (false, true));
+    }
+
+    #[test]
+    fn clear_display_keeps_registers() {
+        let mut core = core_with(&[ld_vx_byte(4, 9), ld_i(0x50), drw(0, 0, 5)]);
+        run(&mut core, 3);
+        core.clear_dirty();
+        let cpu = core.save_cpu();
+
+        core.clear_display();
+        assert!(core.display.iter().all(|&row| row == 0));
+        assert!(core.display_dirty());
+        assert!(core.has_drawn());
+        assert_eq!(core.save_cpu(), cpu);
+    }
+
+    #[test]
+    fn profile_name_follows_preset() {
+        assert_eq!(Core::new(&[], false).profile_name(), "Modern");
+        assert_eq!(Core::with_quirks(&[], Quirks::cosmac_vip()).profile_name(), "COSMAC VIP");
+        assert_eq!(Core::new(&[], true).profile_name(), "Custom");
+
+        let core = CoreBuilder::new().quirks(Quirks::cosmac_vip()).profile_name("VIP hybrid").build().unwrap();
+        assert_eq!(core.profile_name(), "VIP hybrid");
+    }
+
+    #[test]
+    fn seeded_rnd_is_pinned() {
+        let program = to_bytes(&[rnd(0, 0xFF), rnd(1, 0xFF), rnd(2, 0xFF), rnd(3, 0xFF), rnd(4, 0x0F)]);
+        let mut core = Core::with_seed(&program, false, 42);
+        run(&mut core, 5);
+        // xorshift64* after splitmix64(42), the last masked with 0x0F
+        assert_eq!(&core.registers()[..5], &[49, 144, 124, 69, 205 & 0x0F]);
+    }
+
+    #[test]
+    fn timers_tick_while_awaiting_key() {
+        let mut core = core_with(&[ld_vx_byte(0, 10), ld_dt_vx(0), ld_st_vx(0), ld_vx_k(1)]);
+        run(&mut core, 4);
+        assert_eq!(core.pc(), 0x206);
+        for _ in 0..3 {
+            run(&mut core, 5);
+            core.tick_timers(1);
+        }
+        assert_eq!(core.pc(), 0x206);
+        assert_eq!((core.delay_timer(), core.sound_timer()), (7, 7));
+    }
+
+    #[test]
+    fn odd_length_rom_keeps_its_last_byte() {
+        let core = Core::new(&[0x60, 0x01, 0xAB], false);
+        assert_eq!(core.ram_slice()[0x200..0x204], [0x60, 0x01, 0xAB, 0x00]);
+    }
+
+    #[test]
+    fn advance_frame_replays_identically() {
+        // Random sprites moved by key 5, under display wait so draws would normally pend
+        let program = to_bytes(&[rnd(0, 0x3F), rnd(1, 0x1F), ld_vx_byte(2, 5), sknp(2), add_vx_byte(0, 1), ld_i(0x50), drw(0, 1, 5), jp(0x200)]);
+        let replay = || {
+            let mut core = CoreBuilder::new().program(&program).quirks(Quirks::cosmac_vip()).seed(9).build().unwrap();
+            let mut screens = Vec::new();
+            for frame in 0..30 {
+                let mut keys = [false; 16];
+                keys[5] = frame % 3 == 0;
+                assert_eq!(core.advance_frame(keys, 11, 1), Ok(FrameOutcome::BudgetExhausted));
+                assert!(!core.take_draw_pending());
+                screens.push(core.display);
+            }
+            (screens, core.save_cpu(), core.instruction_count())
+        };
+        let first = replay();
+        assert_eq!(first.2, 30 * 11);
+        assert_eq!(first, replay());
+    }
+
+    #[test]
+    fn ram_mut_edits_reach_the_program() {
+        // 6005 becomes 6007 before it runs, index 0 being address 0x200
+        let mut core = core_with(&[ld_vx_byte(0, 5)]);
+        core.ram_mut()[1] = 0x07;
+        run(&mut core, 1);
+        assert_eq!(core.registers()[0], 7);
+        assert_eq!(core.ram_mut().len(), RAM_SIZE - 0x200);
+    }
+
+    #[test]
+    fn always_hires_scales_lores_output() {
+        let program = to_bytes(&[ld_vx_byte(0, 62), ld_vx_byte(1, 1), ld_i(0x50), drw(0, 1, 5), drw(0, 1, 5)]);
+        let mut native = Core::new(&program, false);
+        let mut scaled = CoreBuilder::new().program(&program).always_hires(true).build().unwrap();
+        for core in [&mut native, &mut scaled] {
+            run(core, 4);
+            core.take_dirty_region();
+        }
+        assert_eq!(scaled.display_dimensions(), (128, 64));
+        // The 0's top row is 0xF0, clipped at the right edge after two pixels
+        for x in 0..HIRES_WIDTH {
+            assert_eq!(scaled.pixel(x, 2), native.pixel(x / 2, 1), "x {}", x);
+            assert_eq!(scaled.pixel(x, 3), scaled.pixel(x, 2));
+        }
+        assert!(scaled.pixel(127, 2) && !scaled.pixel(123, 2));
+        assert_eq!(scaled.last_draw_rect(), Some((124, 2, 16, 10)));
+
+        // Redrawing collides exactly like the real lores screen
+        for core in [&mut native, &mut scaled] {
+            run(core, 1);
+        }
+        assert_eq!(scaled.registers()[0xF], 1);
+        assert_eq!(scaled.last_draw_collisions(), native.last_draw_collisions());
+        assert_eq!(native.take_dirty_region(), Some((62, 1, 2, 5)));
+        assert_eq!(scaled.take_dirty_region(), Some((124, 2, 4, 10)));
+    }
+
+    #[test]
+    fn add_byte_to_vf_wraps_without_carry() {
+        // 0xF0 + 0x20 wraps to 0x10 and VF keeps just that, no carry written over it
+        let mut core = core_with(&[ld_vx_byte(0xF, 0xF0), add_vx_byte(0xF, 0x20), add_vx_byte(0xF, 0x01)]);
+        run(&mut core, 2);
+        assert_eq!(core.registers()[0xF], 0x10);
+        run(&mut core, 1);
+        assert_eq!(core.registers()[0xF], 0x11);
+    }
+
+    #[test]
+    fn add_byte_leaves_vf_alone() {
+        for x in 0..0xF {
+            for vf in [0x00, 0x01, 0xAB] {
+                let mut core = core_with(&[ld_vx_byte(0xF, vf), ld_vx_byte(x, 0xFF), add_vx_byte(x, 0x02)]);
+                run(&mut core, 3);
+                assert_eq!(core.registers()[x as usize], 0x01, "V{:X}", x);
+                assert_eq!(core.registers()[0xF], vf, "V{:X}", x);
+            }
+        }
+    }
+
+    #[test]
+    fn cycle_reports_errors() {
+        // 8XY8 doesn't exist, nor do E000 and F0FF
+        for opcode in [0x8128, 0xE000, 0xF0FF] {
+            let mut core = core_with(&[opcode]);
+            assert_eq!(core.cycle(&[]), Err(Chip8Error::InvalidOpcode(opcode)));
+        }
+
+        let mut core = core_with(&[ret()]);
+        assert_eq!(core.cycle(&[]), Err(Chip8Error::StackUnderflow));
+
+        // Calls itself until the stack runs out
+        let mut core = core_with(&[call(0x200)]);
+        run(&mut core, MAX_STACK);
+        assert_eq!(core.cycle(&[]), Err(Chip8Error::StackOverflow));
+    }
+
+    #[test]
+    fn shift_quirk_picks_the_source_register() {
+        // V0 = 0x81 and V1 = 0x02, so the shifted-out bit tells which register was used
+        let cases = [
+            (shr(0, 1), false, 0x40, 1),
+            (shr(0, 1), true, 0x01, 0),
+            (shl(0, 1), false, 0x02, 1),
+            (shl(0, 1), true, 0x04, 0),
+        ];
+        for (opcode, legacy, vx, vf) in cases {
+            let mut core = Core::new(&to_bytes(&[ld_vx_byte(0, 0x81), ld_vx_byte(1, 0x02), opcode]), legacy);
+            run(&mut core, 3);
+            assert_eq!((core.registers()[0], core.registers()[0xF]), (vx, vf), "{:04X} legacy {}", opcode, legacy);
+            assert_eq!(core.registers()[1], 0x02);
+        }
+    }
+
+    #[test]
+    fn jump_offset_quirk_picks_the_register() {
+        // V0 = 0x10 and V2 = 0x05, and B230's X is 2
+        let program = [ld_vx_byte(0, 0x10), ld_vx_byte(2, 0x05), jp_v0(0x230)];
+        for (jump_offset_uses_vx, pc) in [(false, 0x240), (true, 0x235)] {
+            let mut core = core_with_quirks(&program, Quirks { jump_offset_uses_vx, ..Quirks::default() });
+            run(&mut core, 3);
+            assert_eq!(core.pc(), pc, "quirk {}", jump_offset_uses_vx);
+        }
+    }
+
+    #[test]
+    fn load_store_quirk_increments_i() {
+        for (load_store_increments_i, i) in [(false, 0x300), (true, 0x303)] {
+            let quirks = Quirks { load_store_increments_i, ..Quirks::default() };
+            let program = [ld_vx_byte(0, 1), ld_vx_byte(1, 2), ld_vx_byte(2, 3), ld_i(0x300), ld_i_vx(2)];
+            let mut core = core_with_quirks(&program, quirks);
+            run(&mut core, 5);
+            assert_eq!(core.i(), i, "quirk {}", load_store_increments_i);
+            assert_eq!([0x300, 0x301, 0x302, 0x303].map(|addr| core.read_byte(addr)), [1, 2, 3, 0]);
+
+            let mut core = core_with_quirks(&[ld_i(0x50), ld_vx_i(2)], quirks);
+            run(&mut core, 2);
+            assert_eq!(core.i(), i - 0x300 + 0x50, "quirk {}", load_store_increments_i);
+            // The font's 0 starts F0 90 90
+            assert_eq!(&core.registers()[..4], &[0xF0, 0x90, 0x90, 0x00]);
+        }
+    }
+
+    #[test]
+    fn logic_quirk_resets_vf() {
+        // V0 = 0x0C, V1 = 0x0A and VF = 1 going in
+        let cases = [(or(0, 1), 0x0E), (and(0, 1), 0x08), (xor(0, 1), 0x06)];
+        for (opcode, result) in cases {
+            for (logic_resets_vf, vf) in [(false, 1), (true, 0)] {
+                let program = [ld_vx_byte(0, 0x0C), ld_vx_byte(1, 0x0A), ld_vx_byte(0xF, 1), opcode];
+                let mut core = core_with_quirks(&program, Quirks { logic_resets_vf, ..Quirks::default() });
+                run(&mut core, 4);
+                assert_eq!((core.registers()[0], core.registers()[0xF]), (result, vf), "{:04X} quirk {}", opcode, logic_resets_vf);
+            }
+        }
+        // With X = F the reset comes after the result, so VF ends up 0 rather than the
+        // result of VF = 0x07 with V1 = 0x0A. Without the quirk VF keeps the result.
+        let cases = [(or(0xF, 1), 0x0F), (and(0xF, 1), 0x02), (xor(0xF, 1), 0x0D)];
+        for (opcode, result) in cases {
+            for (logic_resets_vf, vf) in [(false, result), (true, 0)] {
+                let program = [ld_vx_byte(0xF, 0x07), ld_vx_byte(1, 0x0A), opcode];
+                let mut core = core_with_quirks(&program, Quirks { logic_resets_vf, ..Quirks::default() });
+                run(&mut core, 3);
+                assert_eq!(core.registers()[0xF], vf, "{:04X} quirk {}", opcode, logic_resets_vf);
+            }
+        }
+    }
+
+    #[test]
+    fn display_wait_flags_each_draw_once() {
+        let program = [ld_i(0x50), drw(0, 0, 5), add_vx_byte(0, 1)];
+        let mut core = core_with_quirks(&program, Quirks { display_wait: true, ..Quirks::default() });
+        run(&mut core, 1);
+        assert!(!core.take_draw_pending());
+        run(&mut core, 1);
+        assert!(core.take_draw_pending());
+        assert!(!core.take_draw_pending());
+        run(&mut core, 1);
+        assert!(!core.take_draw_pending());
+
+        // Never set without the quirk
+        let mut core = core_with(&program);
+        run(&mut core, 2);
+        assert!(!core.take_draw_pending());
+    }
+
+    #[test]
+    fn wrapping_quirk_wraps_overflowing_pixels() {
+        // The 0's top row F0 at x = 62 runs two pixels off the right edge, and its last
+        // two rows off the bottom from y = 29
+        let program = [ld_vx_byte(0, 62), ld_vx_byte(1, 29), ld_i(0x50), drw(0, 1, 5)];
+        for sprite_wrapping in [false, true] {
+            let mut core = core_with_quirks(&program, Quirks { sprite_wrapping, ..Quirks::default() });
+            run(&mut core, 4);
+            let row = |y| [60, 61, 62, 63, 0, 1, 2].map(|x| core.pixel(x, y));
+            assert_eq!(row(29), [false, false, true, true, sprite_wrapping, sprite_wrapping, false]);
+            // 90 puts its second pixel at 65, which only lands on screen as x = 1 when wrapping
+            assert_eq!(row(31), [false, false, true, false, false, sprite_wrapping, false]);
+            assert_eq!(row(1), [false, false, sprite_wrapping, sprite_wrapping, sprite_wrapping, sprite_wrapping, false]);
+        }
+    }
+
+    #[test]
+    fn hires_switches_dimensions_and_draws_at_full_size() {
+        // 00FF, then the 0 at (100, 40), then 00FE
+        let program = [0x00FF, ld_vx_byte(0, 100), ld_vx_byte(1, 40), ld_i(0x50), drw(0, 1, 5), 0x00FE];
+        let mut core = core_with(&program);
+        assert_eq!(core.display_dimensions(), (64, 32));
+        run(&mut core, 1);
+        assert_eq!(core.display_dimensions(), (128, 64));
+        run(&mut core, 4);
+        assert_eq!(core.registers()[0xF], 0);
+        assert_eq!([99, 100, 103, 104].map(|x| core.pixel(x, 40)), [false, true, true, false]);
+        assert_eq!(core.display[40], 0xF << 100);
+        run(&mut core, 1);
+        assert_eq!(core.display_dimensions(), (64, 32));
+    }
+
+    #[test]
+    fn scrolls_move_pixels_by_resolution() {
+        // A one-pixel sprite at (10, 5), the 80 after the program
+        let lores = [(0x00C3, (10, 8)), (0x00FB, (12, 5)), (0x00FC, (8, 5))];
+        let hires = [(0x00C3, (10, 8)), (0x00FB, (14, 5)), (0x00FC, (6, 5))];
+        for (mode, cases) in [(0x00FE, lores), (0x00FF, hires)] {
+            for (scroll, (x, y)) in cases {
+                let program = [mode, ld_vx_byte(0, 10), ld_vx_byte(1, 5), ld_i(0x20C), drw(0, 1, 1), scroll, 0x8000];
+                let mut core = core_with(&program);
+                run(&mut core, 6);
+                assert!(core.pixel(x, y), "{:04X} after {:04X}", scroll, mode);
+                assert!(!core.pixel(10, 5), "{:04X} after {:04X}", scroll, mode);
+                let lit: u32 = core.display.iter().map(|row| row.count_ones()).sum();
+                assert_eq!(lit, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn exit_halts_in_place() {
+        let mut core = core_with(&[ld_vx_byte(0, 1), 0x00FD, ld_vx_byte(0, 2)]);
+        run(&mut core, 2);
+        assert!(core.is_halted());
+        assert_eq!((core.pc(), core.instruction_count()), (0x204, 2));
+        run(&mut core, 10);
+        assert_eq!((core.pc(), core.instruction_count()), (0x204, 2));
+        assert_eq!(core.registers()[0], 1);
+    }
+
+    #[test]
+    fn dxy0_draws_16x16_in_hires() {
+        // A diagonal, with row r lighting column r
+        let mut program = vec![0x00FF, ld_i(0x20A), drw(0, 0, 0), drw(0, 0, 0), jp(0x208)];
+        program.extend((0..16).map(|r| 0x8000 >> r));
+        let mut core = core_with(&program);
+        run(&mut core, 3);
+        for y in 0..16 {
+            for x in 0..17 {
+                assert_eq!(core.pixel(x, y), x == y, "({}, {})", x, y);
+            }
+        }
+        assert_eq!(core.registers()[0xF], 0);
+        assert_eq!(core.last_draw_rect(), Some((0, 0, 16, 16)));
+
+        // Drawing it again erases all 16 pixels
+        run(&mut core, 1);
+        assert_eq!(core.registers()[0xF], 1);
+        assert!(core.display.iter().all(|&row| row == 0));
+    }
+
+    #[test]
+    fn rpl_flags_round_trip_registers() {
+        // X = 9 is past the 8 flags, so only V0..V7 are stored and restored
+        let mut program: Vec<u16> = (0..10).map(|x| ld_vx_byte(x, x + 1)).collect();
+        program.push(0xF975);
+        program.extend((0..10).map(|x| ld_vx_byte(x, 0xEE)));
+        program.push(0xF985);
+        let mut core = core_with(&program);
+        run(&mut core, 11);
+        assert_eq!(core.rpl_flags(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+        run(&mut core, 11);
+        assert_eq!(&core.registers()[..10], &[1, 2, 3, 4, 5, 6, 7, 8, 0xEE, 0xEE]);
+
+        // Flags set by the frontend are what FX85 reads back
+        let mut core = core_with(&[ld_vx_byte(2, 3), 0xF185]);
+        core.set_rpl_flags([9; NUM_RPL_FLAGS]);
+        run(&mut core, 2);
+        assert_eq!(&core.registers()[..3], &[9, 9, 3]);
+    }
+
+    #[test]
+    fn big_font_points_i_at_the_digit() {
+        let mut core = core_with(&[ld_vx_byte(3, 5), 0xF330]);
+        run(&mut core, 2);
+        assert_eq!(core.i(), 0xA0 + 5 * 10);
+        let glyph: Vec<u8> = (0..10).map(|offset| core.read_byte(core.i() + offset)).collect();
+        assert_eq!(glyph, [0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C]);
+    }
+
+    #[test]
+    fn planes_draw_and_clear_independently() {
+        let mut core = core_with(&[0xF201, ld_i(0x50), drw(0, 0, 5), 0xF101, drw(0, 0, 5), cls()]);
+        run(&mut core, 3);
+        assert_eq!((core.pixel_color(0, 0), core.pixel(0, 0)), (2, false));
+        // Plane 1 was still empty, so no collision
+        run(&mut core, 2);
+        assert_eq!((core.pixel_color(0, 0), core.registers()[0xF]), (3, 0));
+        run(&mut core, 1);
+        assert_eq!(core.pixel_color(0, 0), 2);
+
+        // With both selected, plane 2 takes the 5 bytes after plane 1's, the font's 1
+        let mut core = core_with(&[0xF301, ld_i(0x50), drw(0, 0, 5)]);
+        run(&mut core, 3);
+        assert_eq!([0, 1, 2, 3, 4].map(|x| core.pixel_color(x, 0)), [1, 1, 3, 1, 0]);
+    }
+
+    #[test]
+    fn audio_pattern_and_pitch_load() {
+        // The 16 pattern bytes 00 11 .. FF sit right after the program
+        let mut program = vec![ld_i(0x208), 0xF002, ld_vx_byte(0, 112), 0xF03A];
+        program.extend((0..8).map(|pair| pair * 0x2222 + 0x0011));
+        let mut core = core_with(&program);
+        assert_eq!(core.audio_pattern().1, 64);
+        assert_eq!(core.audio_sample_rate(), 4000.0);
+        run(&mut core, 4);
+        let expected: Vec<u8> = (0..16).map(|n| n * 0x11).collect();
+        assert_eq!(core.audio_pattern(), (&expected[..].try_into().unwrap(), 112));
+        // 48 steps of pitch is an octave
+        assert_eq!(core.audio_sample_rate(), 8000.0);
+    }
+
+    #[test]
+    fn long_load_i_takes_two_words() {
+        let mut core = core_with(&[0xF000, 0x1234]);
+        run(&mut core, 1);
+        assert_eq!((core.i(), core.pc()), (0x1234, 0x204));
+
+        // Skips step over both words, V0 is 0 so 3000 skips
+        let mut core = core_with(&[se_vx_byte(0, 0), 0xF000, 0x1234, ld_vx_byte(1, 1)]);
+        run(&mut core, 2);
+        assert_eq!((core.i(), core.registers()[1]), (0, 1));
+    }
+
+    #[test]
+    fn scroll_up_moves_only_selected_planes() {
+        // Draws the 0 and 1 glyph tops to rows 30 and 31 of planes 1 and 2, then 00D3
+        // with just plane 1 selected
+        let program = [ld_vx_byte(0, 10), ld_vx_byte(1, 30), ld_i(0x50), 0xF301, drw(0, 1, 5), 0xF101, 0x00D3];
+        let mut core = core_with(&program);
+        run(&mut core, 7);
+        let row = |y| [10, 11, 12, 13].map(|x| core.pixel_color(x, y));
+        assert_eq!(row(27), [1, 1, 1, 1]);
+        assert_eq!(row(28), [1, 0, 0, 1]);
+        assert_eq!(row(29), [0, 0, 0, 0]);
+        assert_eq!(row(30), [0, 0, 2, 0]);
+        assert_eq!(row(31), [0, 2, 2, 0]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn core_state_round_trips_through_serde() {
+        let mut core = core_with(&[ld_vx_byte(0, 7), ld_i(0x50), drw(0, 0, 5), call(0x20A), jp(0x208), add_vx_byte(0, 1), ret()]);
+        run(&mut core, 4);
+        let saved = core.save_state();
+        let json = serde_json::to_string(&saved).unwrap();
+        run(&mut core, 10);
+
+        let state: CoreState = serde_json::from_str(&json).unwrap();
+        assert_eq!(state, saved);
+        core.load_state(state).unwrap();
+        assert_eq!(core.save_state(), saved);
+
+        // An out-of-range stack pointer gets through serde but not load_state
+        let deep = json.replacen("\"sp\":1", "\"sp\":17", 1);
+        let state: CoreState = serde_json::from_str(&deep).unwrap();
+        assert_eq!(core.load_state(state), Err(Chip8Error::BadSnapshot));
+
+        // A plane of the wrong length is rejected rather than padded
+        let short = json.replacen("\"display\":[", "\"display\":[0,", 1);
+        assert!(serde_json::from_str::<CoreState>(&short).is_err());
+    }
+
+    #[test]
+    fn sound_follows_the_timer() {
+        let changes = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut core = core_with(&[ld_vx_byte(0, 2), ld_st_vx(0)]);
+        let seen = changes.clone();
+        core.on_sound_change(move |active| seen.lock().unwrap().push(active));
+        run(&mut core, 2);
+        assert!(core.is_sound_active() && core.sound_active());
+        core.decrement_timers();
+        assert!(core.is_sound_active());
+        core.decrement_timers();
+        assert!(!core.is_sound_active() && !core.sound_active());
+        core.decrement_timers();
+        assert_eq!(*changes.lock().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn core_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Core>();
+    }
+
+    #[test]
+    fn stack_holds_sixteen_calls() {
+        // Each call goes to the next instruction, another call
+        let program: Vec<u16> = (1..=MAX_STACK as u16 + 1).map(|n| call(0x200 + 2 * n)).collect();
+        let mut core = core_with(&program);
+        run(&mut core, MAX_STACK);
+        let cpu = core.save_cpu();
+        assert_eq!(cpu.sp, MAX_STACK);
+        assert_eq!((cpu.stack[0], cpu.stack[MAX_STACK - 1]), (0x202, 0x220));
+        assert_eq!(core.cycle(&[]), Err(Chip8Error::StackOverflow));
+        assert_eq!(core.save_cpu().stack, cpu.stack);
+
+        // A bare 00EE is an error, not a panic
+        let mut core = core_with(&[ret()]);
+        assert_eq!(core.cycle(&[]), Err(Chip8Error::StackUnderflow));
+        assert_eq!(core.save_cpu().sp, 0);
+    }
+
+    #[test]
+    fn memory_access_wraps_at_the_top_of_ram() {
+        // BCD of 123 at I = 0xFFFE runs its last digit over to 0x0000
+        let program = [ld_vx_byte(0, 123), 0xF000, 0xFFFE, ld_b_vx(0), drw(1, 1, 3), ld_vx_i(2)];
+        let mut core = core_with(&program);
+        run(&mut core, 3);
+        assert_eq!([0xFFFE, 0xFFFF, 0x0000].map(|addr| core.read_byte(addr)), [1, 2, 3]);
+        // The sprite reads the same three bytes back
+        run(&mut core, 1);
+        assert_eq!([7, 6].map(|x| core.pixel(x, 0)), [true, false]);
+        assert_eq!([6, 7].map(|x| core.pixel(x, 1)), [true, false]);
+        assert_eq!([6, 7].map(|x| core.pixel(x, 2)), [true, true]);
+        run(&mut core, 1);
+        assert_eq!(&core.registers()[..3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn await_key_waits_for_release() {
+        let mut core = core_with(&[ld_vx_k(3), ld_vx_byte(4, 1)]);
+        run(&mut core, 1);
+        assert_eq!(core.pc(), 0x200);
+        // Pressed and held, still waiting
+        for _ in 0..3 {
+            core.cycle(&[0x7]).unwrap();
+            assert_eq!((core.pc(), core.registers()[3]), (0x200, 0));
+        }
+        // Released, V3 gets the key and the program carries on
+        core.cycle(&[]).unwrap();
+        assert_eq!((core.pc(), core.registers()[3]), (0x202, 0x7));
+        core.cycle(&[]).unwrap();
+        assert_eq!(core.registers()[4], 1);
+    }
+
+    #[test]
+    fn font_address_uses_the_low_nibble() {
+        for (value, digit) in [(0x00, 0), (0x0F, 0xF), (0x34, 4), (0xFF, 0xF)] {
+            let mut core = core_with(&[ld_vx_byte(2, value), ld_f_vx(2)]);
+            run(&mut core, 2);
+            assert_eq!(core.i(), 0x50 + digit * 5, "{:02X}", value);
+        }
+    }
+
+    #[test]
+    fn breakpoint_stops_before_executing() {
+        // V0 counts up in a loop back to 0x200
+        let mut core = core_with(&[add_vx_byte(0, 1), ld_vx_byte(1, 2), jp(0x200)]);
+        core.add_breakpoint(0x202);
+        assert_eq!(core.run_cycles(10, &[]), Ok(CycleOutcome::BreakpointHit(0x202)));
+        assert_eq!((core.pc(), core.registers()[1]), (0x202, 0));
+        // Continuing runs the instruction there, then stops on the next lap
+        assert_eq!(core.cycle_checked(&[]), Ok(CycleOutcome::Executed));
+        assert_eq!(core.registers()[1], 2);
+        assert_eq!(core.run_cycles(10, &[]), Ok(CycleOutcome::BreakpointHit(0x202)));
+        assert_eq!(core.registers()[0], 2);
+
+        // Plain cycles going around the loop don't leave the breakpoint disarmed
+        core.remove_breakpoint(0x202);
+        core.add_breakpoint(0x200);
+        run(&mut core, 2);
+        assert_eq!(core.cycle_checked(&[]), Ok(CycleOutcome::BreakpointHit(0x200)));
+        run(&mut core, 3);
+        assert_eq!(core.cycle_checked(&[]), Ok(CycleOutcome::BreakpointHit(0x200)));
+    }
+
+    #[test]
+    fn step_runs_exactly_one_instruction() {
+        let mut core = core_with(&[ld_vx_byte(0, 5), ld_vx_byte(1, 6)]);
+        core.step(&[]).unwrap();
+        assert_eq!((core.pc(), core.instruction_count()), (0x202, 1));
+        assert_eq!(&core.registers()[..2], &[5, 0]);
+    }
+
+    #[test]
+    fn run_cycles_stops_early() {
+        // The bad opcode is the third instruction, so the last load never runs
+        let mut core = core_with(&[ld_vx_byte(0, 5), ld_vx_byte(1, 6), 0xE000, ld_vx_byte(2, 7)]);
+        assert_eq!(core.run_cycles(100, &[]), Err(Chip8Error::InvalidOpcode(0xE000)));
+        assert_eq!((core.instruction_count(), core.registers()[2]), (2, 0));
+
+        let mut core = core_with(&[ld_vx_byte(0, 5), 0x00FD, ld_vx_byte(2, 7)]);
+        assert_eq!(core.run_cycles(100, &[]), Ok(CycleOutcome::Executed));
+        assert!(core.is_halted());
+        assert_eq!(core.instruction_count(), 2);
+    }
+
+    #[test]
+    fn watchpoint_reports_bcd_writes() {
+        let mut core = core_with(&[ld_vx_byte(0, 254), ld_i(0x300), ld_b_vx(0)]);
+        core.write_byte(0x301, 0xAA);
+        core.add_watchpoint(0x301, 0x302);
+        assert_eq!(core.run_cycles(2, &[]), Ok(CycleOutcome::Executed));
+        // 254 writes 2, 5, 4 and 0x300 isn't watched, so the first hit is the 5
+        assert_eq!(core.cycle_checked(&[]), Ok(CycleOutcome::WatchpointHit { addr: 0x301, old: 0xAA, new: 5 }));
+        assert_eq!(core.read_byte(0x302), 4);
+
+        // Removed ranges stay quiet
+        let mut core = core_with(&[ld_i(0x300), ld_b_vx(0)]);
+        core.add_watchpoint(0x300, 0x300);
+        core.remove_watchpoint(0x300, 0x300);
+        assert_eq!(core.run_cycles(2, &[]), Ok(CycleOutcome::Executed));
+    }
+
+    #[test]
+    fn trace_records_executed_instructions() {
+        let mut core = core_with(&[ld_vx_byte(0, 1), add_vx_byte(0, 2), jp(0x200)]);
+        run(&mut core, 2);
+        assert!(core.trace().is_empty());
+        core.enable_trace(true);
+        run(&mut core, 3);
+        let entries: Vec<(u16, u16)> = core.trace().iter().map(|entry| (entry.pc, entry.opcode)).collect();
+        assert_eq!(entries, [(0x204, 0x1200), (0x200, 0x6001), (0x202, 0x7002)]);
+        assert_eq!(core.trace()[1].mnemonic(), "LD V0, 0x01");
+
+        // Only the most recent TRACE_LEN are kept
+        run(&mut core, 3 * TRACE_LEN);
+        assert_eq!(core.trace().len(), TRACE_LEN);
+        assert_eq!(core.trace().last().unwrap().pc, 0x202);
+        core.enable_trace(false);
+        assert!(core.trace().is_empty());
+    }
+
+    #[test]
+    fn cycle_count_follows_a_loop() {
+        // Counts V0 down from 10, three instructions a lap, then spins on the jump
+        let program = [ld_vx_byte(0, 10), add_vx_byte(0, 0xFF), se_vx_byte(0, 0), jp(0x202), jp(0x208)];
+        let mut core = core_with(&program);
+        while !core.is_idle() {
+            core.cycle(&[]).unwrap();
+        }
+        // The load, ten laps less the last jump back, then the first jump to self
+        assert_eq!(core.cycle_count(), 1 + 10 * 3 - 1 + 1);
+        assert_eq!(core.cycle_count(), core.instruction_count());
+        core.reset();
+        assert_eq!(core.cycle_count(), 0);
+    }
+
+    #[test]
+    fn oversized_rom_is_an_error() {
+        let max = RAM_SIZE - START_ADDR as usize;
+        let fits = vec![0xAB; max];
+        let core = Core::try_new(&fits, false).unwrap();
+        assert_eq!(core.read_byte(0xFFFF), 0xAB);
+
+        let too_big = vec![0; max + 1];
+        let expected = Chip8Error::RomTooLarge { size: max + 1, max };
+        assert_eq!(Core::try_new(&too_big, false).err(), Some(expected));
+        assert_eq!(CoreBuilder::new().program(&too_big).build().err(), Some(expected));
+        // Reloading keeps the current program on failure
+        let mut core = core_with(&[ld_vx_byte(0, 1)]);
+        assert_eq!(core.reset_and_reload(&too_big), Err(expected));
+        assert_eq!(core.read_byte(0x200), 0x60);
+    }
+
+    #[test]
+    fn from_reader_loads_the_stream() {
+        let rom = to_bytes(&[ld_vx_byte(0, 0x12), jp(0x202)]);
+        let core = Core::from_reader(std::io::Cursor::new(&rom), true).unwrap();
+        assert_eq!([0x200, 0x201, 0x202, 0x203, 0x204].map(|addr| core.read_byte(addr)), [0x60, 0x12, 0x12, 0x02, 0]);
+        assert!(core.quirks().shift_uses_vy);
+
+        // Read errors come back as Io
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::UnexpectedEof.into())
+            }
+        }
+        assert_eq!(Core::from_reader(Broken, false).err(), Some(Chip8Error::Io(io::ErrorKind::UnexpectedEof)));
+    }
+
+    #[test]
+    fn builder_applies_its_options() {
+        let mut core = CoreBuilder::new()
+            .program(&to_bytes(&[ld_vx_byte(0, 1)]))
+            .quirks(Quirks::cosmac_vip())
+            .legacy(false)
+            .profile_name("Mine")
+            .trace(true)
+            .build()
+            .unwrap();
+        assert_eq!(core.quirks(), Quirks { shift_uses_vy: false, ..Quirks::cosmac_vip() });
+        assert_eq!(core.profile_name(), "Mine");
+        run(&mut core, 1);
+        assert_eq!(core.trace(), [TraceEntry { pc: 0x200, opcode: 0x6001 }]);
+
+        // legacy before quirks is overridden by the preset
+        let core = CoreBuilder::new().legacy(false).quirks(Quirks::cosmac_vip()).build().unwrap();
+        assert!(core.quirks().shift_uses_vy);
+        assert_eq!(core.profile_name(), "COSMAC VIP");
+    }
+
+    #[test]
+    fn key_skips_read_the_bitmask() {
+        // V0 = 5, then V1 counts the instructions that weren't skipped
+        let program = [ld_vx_byte(0, 5), skp(0), add_vx_byte(1, 1), sknp(0), add_vx_byte(1, 0x10)];
+        for (keys, v1) in [(0, 0x01), (1 << 5, 0x10), (!(1 << 5), 0x01), (u16::MAX, 0x10)] {
+            let mut core = core_with(&program);
+            for _ in 0..5 {
+                core.cycle_keys(keys).unwrap();
+            }
+            assert_eq!(core.registers()[1], v1, "keys {:016b}", keys);
+        }
+    }
+
+    #[test]
+    fn restored_state_repeats_random_draws() {
+        let mut core = Core::with_seed(&to_bytes(&[rnd(0, 0xFF), jp(0x200)]), false, 42);
+        run(&mut core, 4);
+        let saved = core.save_state();
+        let bytes = core.to_bytes();
+        let draws = |core: &mut Core| (0..5).map(|_| { run(core, 2); core.registers()[0] }).collect::<Vec<_>>();
+        let expected = draws(&mut core);
+
+        // A core seeded differently picks up the saved generator
+        let mut restored = Core::with_seed(&[], false, 7);
+        restored.load_state(saved).unwrap();
+        assert_eq!(draws(&mut restored), expected);
+        assert_eq!(draws(&mut Core::from_bytes(&bytes).unwrap()), expected);
+    }
+
+    #[test]
+    fn ram_accessors_cover_every_address() {
+        let mut core = core_with(&[]);
+        core.write_byte(0x300, 0x42);
+        // Past 0xFFF is ordinary RAM too, there's no out-of-range address to reject
+        core.write_byte(0xFFFF, 0x99);
+        assert_eq!((core.read_byte(0x300), core.read_byte(0xFFFF)), (0x42, 0x99));
+        assert_eq!(core.ram_slice().len(), RAM_SIZE);
+        assert_eq!((core.ram_slice()[0x300], core.ram_slice()[0xFFFF]), (0x42, 0x99));
+        // The font is where FX29 points
+        assert_eq!(&core.ram_slice()[0x50..0x55], &[0xF0, 0x90, 0x90, 0x90, 0xF0]);
+    }
+
+    #[test]
+    fn dump_state_shows_the_machine() {
+        let mut core = core_with(&[ld_vx_byte(0, 0xAB), ld_vx_byte(0xF, 1), ld_i(0x300), ld_dt_vx(0), call(0x20C), 0, add_vx_byte(0, 2)]);
+        assert!(core.dump_state().contains("stack -"));
+        run(&mut core, 5);
+        let dump = core.dump_state();
+        assert_eq!(dump.lines().count(), 6);
+        for expected in [
+            "PC 0x020C  I 0x0300",
+            "V0-V7 AB 00 00 00 00 00 00 00",
+            "V8-VF 00 00 00 00 00 00 00 01",
+            "DT AB  ST 00",
+            "stack 0x020A",
+            "next 7002 ADD V0, 0x02",
+        ] {
+            assert!(dump.contains(expected), "{:?} not in\n{}", expected, dump);
+        }
+    }
+
+    #[test]
+    fn jump_to_self_reports_idle() {
+        // Loops back to 0x202 once before skipping ahead to the jump at 0x208
+        let mut core = core_with(&[ld_vx_byte(0, 1), add_vx_byte(0, 1), se_vx_byte(0, 3), jp(0x202), jp(0x208)]);
+        while core.pc() != 0x208 {
+            core.cycle(&[]).unwrap();
+            assert!(!core.is_idle(), "at {:#06X}", core.pc());
+        }
+        run(&mut core, 1);
+        assert!(core.is_idle());
+        // It keeps running the jump, it just says so
+        run(&mut core, 5);
+        assert_eq!((core.pc(), core.instruction_count()), (0x208, 12));
+        core.reset();
+        assert!(!core.is_idle());
+    }
+
+    #[test]
+    fn collisions_count_every_erased_pixel() {
+        // A solid 16x16 block drawn twice in hires, then the 0 drawn over the 8 in lores
+        let mut program = vec![0x00FF, ld_i(0x220), drw(0, 0, 0), drw(0, 0, 0), 0x00FE, ld_i(0x50), drw(0, 0, 5), ld_i(0x78), drw(0, 0, 5)];
+        program.resize(16, 0);
+        program.extend([0xFFFF; 16]);
+        let mut core = core_with(&program);
+        run(&mut core, 3);
+        assert_eq!(core.last_draw_collisions(), 0);
+        run(&mut core, 1);
+        assert_eq!((core.last_draw_collisions(), core.registers()[0xF]), (256, 1));
+
+        run(&mut core, 1);
+        core.take_dirty_region();
+        run(&mut core, 4);
+        // 0 is F0 90 90 90 F0 and 8 is F0 90 F0 90 F0, so they share 14 pixels
+        assert_eq!(core.last_draw_collisions(), 14);
+        assert_eq!(core.take_dirty_region(), Some((0, 0, 4, 5)));
+    }
+
+    #[test]
+    fn display_dirty_tracks_screen_changes() {
+        let program = to_bytes(&[ld_i(0x50), drw(0, 0, 5), ld_vx_byte(0, 1), cls()]);
+        for mut core in [Core::new(&program, false), CoreBuilder::new().program(&program).build().unwrap()] {
+            assert!(!core.display_dirty());
+            run(&mut core, 2);
+            assert!(core.display_dirty());
+            core.clear_dirty();
+            assert!(!core.display_dirty());
+            // Register writes leave the screen alone
+            run(&mut core, 1);
+            assert!(!core.display_dirty());
+            run(&mut core, 1);
+            assert!(core.display_dirty());
+        }
+    }
+
+    #[test]
+    fn packed_display_matches_a_pixel_array() {
+        // Random glyphs at random spots, checked against a plain bool-per-pixel model of
+        // the original clipping draw
+        let program = [rnd(0, 0xFF), rnd(1, 0xFF), rnd(2, 0x0F), ld_f_vx(2), drw(0, 1, 5), jp(0x200)];
+        let mut core = Core::with_seed(&to_bytes(&program), false, 3);
+        let mut model = [[false; SCREEN_WIDTH]; SCREEN_HEIGHT];
+        for _ in 0..200 {
+            run(&mut core, 4);
+            let (x0, y0) = (core.registers()[0] as usize % SCREEN_WIDTH, core.registers()[1] as usize % SCREEN_HEIGHT);
+            let mut collided = false;
+            for row in 0..5 {
+                let bits = core.read_byte(core.i() + row as u16);
+                for col in 0..8 {
+                    let (x, y) = (x0 + col, y0 + row);
+                    if bits & (0x80 >> col) != 0 && x < SCREEN_WIDTH && y < SCREEN_HEIGHT {
+                        collided |= model[y][x];
+                        model[y][x] ^= true;
+                    }
+                }
+            }
+            run(&mut core, 2);
+            assert_eq!(core.registers()[0xF], collided as u8);
+            for (y, row) in model.iter().enumerate() {
+                for (x, &on) in row.iter().enumerate() {
+                    assert_eq!(core.pixel(x, y), on, "({}, {})", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_states_are_rejected() {
+        let mut core = core_with(&[ld_vx_byte(0, 1), call(0x206), 0, ret()]);
+        run(&mut core, 2);
+        let before = core.save_state();
+
+        let mut cpu = core.save_cpu();
+        cpu.sp = MAX_STACK + 1;
+        assert_eq!(core.restore_cpu(cpu.clone()), Err(Chip8Error::BadSnapshot));
+        let mut bad_sp = before.clone();
+        bad_sp.cpu = cpu;
+        let mut bad_planes = before.clone();
+        bad_planes.selected_planes = 0b100;
+        let mut bad_rng = before.clone();
+        bad_rng.rng_state = 0;
+        let mut bad_quirks = before.clone();
+        bad_quirks.quirks = Quirks { i_mask_12bit: true, xo_chip: true, ..Quirks::default() };
+        for state in [bad_sp, bad_planes, bad_rng, bad_quirks] {
+            assert_eq!(core.load_state(state), Err(Chip8Error::BadSnapshot));
+        }
+        // Nothing was applied, and the core still runs
+        assert_eq!(core.save_state(), before);
+        run(&mut core, 1);
+        assert_eq!(core.save_cpu().sp, 0);
+
+        // A full stack is still fine
+        let mut full = core.save_cpu();
+        full.sp = MAX_STACK;
+        assert_eq!(core.restore_cpu(full), Ok(()));
+    }
+
+    #[test]
+    fn run_until_pc_stops_before_the_target() {
+        let mut core = core_with(&[ld_vx_byte(0, 1), add_vx_byte(0, 1), ld_vx_byte(1, 0x42), jp(0x206)]);
+        assert_eq!(core.run_until_pc(0x204, 100, &[]), Ok(true));
+        assert_eq!(core.pc, 0x204);
+        // The 6XNN at the target hasn't run yet
+        assert_eq!(core.registers()[..2], [2, 0]);
+        // Already there, so nothing runs
+        assert_eq!(core.run_until_pc(0x204, 100, &[]), Ok(true));
+        assert_eq!(core.instruction_count(), 2);
+
+        // The jump to self never gets back to 0x200
+        assert_eq!(core.run_until_pc(0x200, 50, &[]), Ok(false));
+        assert_eq!(core.pc, 0x206);
+        assert_eq!(core.registers()[1], 0x42);
+        assert_eq!(core.instruction_count(), 52);
+    }
+
+    #[test]
+    fn frame_cycles_add_up_to_cps_each_second() {
+        for cps in [7, 59, 61, 500, 700, 1000, 1234] {
+            let frames: Vec<usize> = (0..TIMER_HZ).map(|frame| frame_cycles(cps, frame)).collect();
+            assert_eq!(frames.iter().sum::<usize>(), cps, "cps {}", cps);
+            // The remainder is spread one cycle at a time
+            assert!(frames.iter().all(|&n| n == cps / TIMER_HZ || n == cps / TIMER_HZ + 1), "cps {}", cps);
+            // And the next second goes the same way
+            assert!((0..TIMER_HZ).all(|frame| frame_cycles(cps, frame + TIMER_HZ) == frames[frame]));
+        }
+    }
+
+    #[test]
+    fn run_frames_repeats_delay_timer_loops_exactly() {
+        // Counts in V1 how many times a 3-tick delay runs out
+        let program = [
+            ld_vx_byte(0, 3),
+            ld_dt_vx(0),
+            ld_vx_dt(2),
+            se_vx_byte(2, 0),
+            jp(0x204),
+            add_vx_byte(1, 1),
+            jp(0x200),
+        ];
+        let runs: Vec<_> = (0..2)
+            .map(|_| {
+                let mut core = core_with(&program);
+                core.run_frames(700, 30, &[]).unwrap();
+                (core.pc, *core.registers())
+            })
+            .collect();
+        assert_eq!(runs[0], runs[1]);
+        // Each delay is seen running out in the frame after its third tick: frames 3, 6 .. 27
+        assert_eq!(runs[0].1[1], 9);
+    }
+
+    #[test]
+    fn resolution_switch_forgets_the_last_draw() {
+        // A sprite near the bottom right of the hires screen, then back to lores
+        let mut core = core_with(&[0x00FF, ld_vx_byte(0, 120), ld_vx_byte(1, 60), ld_i(0x50), drw(0, 1, 4), 0x00FE]);
+        run(&mut core, 5);
+        assert_eq!(core.last_draw_rect(), Some((120, 60, 8, 4)));
+        run(&mut core, 1);
+        assert_eq!(core.last_draw_rect(), None);
+    }
+
+    #[test]
+    fn two_plane_draws_collide_on_either_plane() {
+        // Lights (0, 0) in one plane, then draws a row into both planes over it
+        for (first_plane, both, vf) in [(1, [0x80, 0x00], 1), (2, [0x00, 0x80], 1), (1, [0x00, 0x80], 0), (2, [0x80, 0x00], 0)] {
+            let program = [0xF001 | first_plane << 8, ld_i(0x220), drw(0, 0, 1), 0xF301, ld_i(0x222), drw(0, 0, 1)];
+            let mut core = core_with(&program);
+            core.write_byte(0x220, 0x80);
+            core.write_byte(0x222, both[0]);
+            core.write_byte(0x223, both[1]);
+            run(&mut core, 6);
+            assert_eq!((core.registers()[0xF], core.last_draw_collisions()), (vf, vf as u16), "plane {} then {:02X?}", first_plane, both);
+        }
+    }
+
+    #[test]
+    fn every_skip_steps_over_a_long_load() {
+        // V0 = 0, V1 = 1 and key 0 held, so each of these skips
+        let skips = [se_vx_byte(0, 0), sne_vx_byte(0, 1), se_vx_vy(0, 0), sne_vx_vy(0, 1), skp(0), sknp(1)];
+        for skip in skips {
+            for (xo_chip, pc) in [(true, 0x208), (false, 0x206)] {
+                let program = [ld_vx_byte(1, 1), skip, 0xF000, 0x1234, ld_vx_byte(2, 2)];
+                let mut core = core_with_quirks(&program, Quirks { xo_chip, ..Quirks::default() });
+                for _ in 0..2 {
+                    core.cycle(&[0]).unwrap();
+                }
+                // Without XO-CHIP F000 is one word and the skip lands on its NNNN
+                assert_eq!(core.pc(), pc, "{:04X} xo_chip {}", skip, xo_chip);
+            }
+        }
+    }
+
+    #[test]
+    fn full_stack_unwinds_in_order() {
+        // 17 returns in a row, with a full stack whose newest entry is the second one
+        let mut core = core_with(&[ret(); 17]);
+        let mut cpu = core.save_cpu();
+        for (depth, addr) in cpu.stack.iter_mut().enumerate() {
+            *addr = 0x202 + 2 * (MAX_STACK - 1 - depth) as u16;
+        }
+        cpu.sp = MAX_STACK;
+        core.restore_cpu(cpu).unwrap();
+
+        for popped in 1..=MAX_STACK {
+            core.cycle(&[]).unwrap();
+            assert_eq!((core.pc(), core.save_cpu().sp), (0x200 + 2 * popped as u16, MAX_STACK - popped));
+        }
+        assert_eq!(core.cycle(&[]), Err(Chip8Error::StackUnderflow));
+    }
+
+    #[test]
+    fn patching_out_the_idle_jump_clears_idle() {
+        let mut core = core_with(&[jp(0x200)]);
+        run(&mut core, 1);
+        assert!(core.is_idle());
+        // Turned into 6000, which runs and moves on
+        core.write_byte(0x200, 0x60);
+        assert!(!core.is_idle());
+        run(&mut core, 1);
+        assert_eq!(core.pc(), 0x202);
+
+        // The same through ram_mut, whose index 1 is 0x201, and a jump elsewhere doesn't
+        // count either
+        let mut core = core_with(&[jp(0x200)]);
+        run(&mut core, 1);
+        core.ram_mut()[1] = 0x02;
+        assert!(!core.is_idle());
+        core.ram_mut()[1] = 0x00;
+        assert!(core.is_idle());
+    }
+
+    #[test]
+    fn run_frame_stops_early_for_each_outcome() {
+        // Each stops on its last instruction with budget to spare
+        let cases = [
+            (vec![ld_vx_byte(0, 1), 0x00FD], Quirks::default(), FrameOutcome::Halted),
+            (vec![ld_vx_byte(0, 1), ld_vx_k(1)], Quirks::default(), FrameOutcome::AwaitingKey),
+            (vec![ld_i(0x50), drw(0, 0, 5)], Quirks { display_wait: true, ..Quirks::default() }, FrameOutcome::DisplayWait),
+            (vec![ld_vx_byte(0, 1), jp(0x202)], Quirks::default(), FrameOutcome::Idle),
+        ];
+        for (program, quirks, outcome) in cases {
+            let mut core = core_with_quirks(&program, quirks);
+            assert_eq!(core.run_frame(100, &[]), Ok(outcome));
+            assert_eq!(core.instruction_count(), 2, "{:?}", outcome);
+        }
+        // Without any of them the whole budget runs
+        let mut core = core_with(&[add_vx_byte(0, 1), jp(0x200)]);
+        assert_eq!(core.run_frame(100, &[]), Ok(FrameOutcome::BudgetExhausted));
+        assert_eq!(core.instruction_count(), 100);
+    }
+
+    #[test]
+    fn delay_timer_loops_are_recognised() {
+        // DT = 3, then waits for it with 3X00 at 0x204 or 4X00 at 0x20E, then V2 = 1
+        let program = [
+            ld_vx_byte(0, 3),
+            ld_dt_vx(0),
+            ld_vx_dt(1),
+            se_vx_byte(1, 0),
+            jp(0x204),
+            ld_dt_vx(0),
+            ld_vx_dt(1),
+            sne_vx_byte(1, 0),
+            jp(0x214),
+            jp(0x20C),
+            ld_vx_byte(2, 1),
+            jp(0x216),
+        ];
+        let mut core = core_with(&program);
+        run(&mut core, 2);
+        for _ in 0..2 {
+            // Wherever the loop is up to, each lap until the timer runs out
+            for _ in 0..10 {
+                assert!(core.is_waiting_on_delay_timer(), "at {:#06X}", core.pc());
+                run(&mut core, 1);
+            }
+            core.tick_timers(3);
+            assert!(!core.is_waiting_on_delay_timer());
+            while core.pc() != 0x20A && core.pc() != 0x214 {
+                run(&mut core, 1);
+            }
+            run(&mut core, 1);
+        }
+        // On to the end, which doesn't read the timer at all
+        run(&mut core, 1);
+        assert_eq!((core.pc(), core.registers()[2]), (0x216, 1));
+    }
+
+    #[test]
+    fn skipping_delay_waits_keeps_the_timing() {
+        // Counts in V2 how many waits of 10 ticks have run out
+        let program = [ld_vx_byte(0, 10), ld_dt_vx(0), ld_vx_dt(1), se_vx_byte(1, 0), jp(0x204), add_vx_byte(2, 1), jp(0x200)];
+        let frames = |skip: bool| {
+            let mut core = core_with(&program);
+            (0..45)
+                .map(|_| {
+                    if !(skip && core.is_waiting_on_delay_timer()) {
+                        core.run_frame(20, &[]).unwrap();
+                    }
+                    core.tick_timers(1);
+                    core.registers()[2]
+                })
+                .collect::<Vec<_>>()
+        };
+        // V2 goes up on the same frames either way
+        assert_eq!(frames(true), frames(false));
+        assert_eq!(frames(true)[44], 4);
+    }
+
+    #[test]
+    fn writes_over_executed_code_are_reported() {
+        let writes = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        // Stores V0 over its own first instruction
+        let mut core = core_with(&[ld_vx_byte(0, 0x12), ld_i(0x200), ld_i_vx(0), jp(0x206)]);
+        let seen = writes.clone();
+        core.on_self_modify(move |addr| seen.lock().unwrap().push(addr));
+        run(&mut core, 3);
+        assert_eq!(*writes.lock().unwrap(), [0x200]);
+
+        // Poking works the same, but only where an instruction was fetched from
+        core.write_byte(0x300, 1);
+        core.write_byte(0x203, 0);
+        assert_eq!(*writes.lock().unwrap(), [0x200, 0x203]);
+        // A reset starts over with nothing fetched
+        core.reset();
+        core.write_byte(0x200, 0x60);
+        assert_eq!(writes.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn display_hash_follows_the_screen() {
+        let mut core = core_with(&[ld_i(0x50), drw(0, 0, 5), drw(0, 0, 5), 0x00FF]);
+        let blank = core.display_hash();
+        // Pinned, since manifests written today have to match later builds
+        assert_eq!(blank, 0x724D_5FE3_3C75_97DF);
+        run(&mut core, 2);
+        let drawn = core.display_hash();
+        assert_ne!(drawn, blank);
+        run(&mut core, 1);
+        assert_eq!(core.display_hash(), blank);
+        // A blank hires screen isn't a blank lores one
+        run(&mut core, 1);
+        assert_ne!(core.display_hash(), blank);
+        core.set_always_hires(true);
+        core.reset();
+        assert_eq!(core.display_hash(), blank);
+    }
+}
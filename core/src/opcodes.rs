@@ -0,0 +1,238 @@
+// What this build can execute, for tooling that needs to ask rather than hard-code it.
+// OpcodeKind::of mirrors Core::decode_and_exec, so anything it returns None for is an
+// invalid opcode there with the default quirks. OpcodeKind::with_quirks also accounts for
+// Quirks::xo_chip, without which the XO-CHIP-only kinds are invalid too.
+
+use crate::Quirks;
+
+// The instruction set an opcode was introduced by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+// One per opcode pattern, named after the disasm mnemonics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpcodeKind {
+    // 0000, a no-op here rather than a machine code call
+    Nop,
+    Cls,
+    Ret,
+    Jp,
+    Call,
+    SeByte,
+    SneByte,
+    SeReg,
+    LdByte,
+    AddByte,
+    LdReg,
+    Or,
+    And,
+    Xor,
+    AddReg,
+    Sub,
+    Shr,
+    Subn,
+    Shl,
+    SneReg,
+    LdI,
+    JpV0,
+    Rnd,
+    Drw,
+    Skp,
+    Sknp,
+    LdVxDt,
+    LdVxK,
+    LdDtVx,
+    LdStVx,
+    AddI,
+    LdF,
+    LdB,
+    // FX55
+    StoreRegs,
+    // FX65
+    LoadRegs,
+    ScrollDown,
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    Low,
+    High,
+    LdHf,
+    // FX75/FX85, the HP48 RPL flags
+    StoreFlags,
+    LoadFlags,
+    ScrollUp,
+    // F000 NNNN
+    LdILong,
+    Plane,
+    Audio,
+    Pitch,
+}
+
+const IMPLEMENTED: [OpcodeKind; 49] = {
+    use OpcodeKind::*;
+    [
+        Nop, Cls, Ret, Jp, Call, SeByte, SneByte, SeReg, LdByte, AddByte, LdReg, Or, And, Xor,
+        AddReg, Sub, Shr, Subn, Shl, SneReg, LdI, JpV0, Rnd, Drw, Skp, Sknp, LdVxDt, LdVxK,
+        LdDtVx, LdStVx, AddI, LdF, LdB, StoreRegs, LoadRegs,
+        ScrollDown, ScrollRight, ScrollLeft, Exit, Low, High, LdHf, StoreFlags, LoadFlags,
+        ScrollUp, LdILong, Plane, Audio, Pitch,
+    ]
+};
+
+// Every kind the interpreter executes, grouped by platform in the order above
+pub fn implemented_opcodes() -> &'static [OpcodeKind] {
+    &IMPLEMENTED
+}
+
+impl OpcodeKind {
+    pub fn of(opcode: u16) -> Option<Self> {
+        use OpcodeKind::*;
+        let rest = opcode & 0x0FFF;
+        Some(match opcode >> 12 {
+            0x0 => match rest {
+                0x000 => Nop,
+                0x0C0..=0x0CF => ScrollDown,
+                0x0D0..=0x0DF => ScrollUp,
+                0x0E0 => Cls,
+                0x0EE => Ret,
+                0x0FB => ScrollRight,
+                0x0FC => ScrollLeft,
+                0x0FD => Exit,
+                0x0FE => Low,
+                0x0FF => High,
+                _ => return None,
+            },
+            0x1 => Jp,
+            0x2 => Call,
+            0x3 => SeByte,
+            0x4 => SneByte,
+            0x5 => SeReg,
+            0x6 => LdByte,
+            0x7 => AddByte,
+            0x8 => match rest & 0x00F {
+                0x0 => LdReg,
+                0x1 => Or,
+                0x2 => And,
+                0x3 => Xor,
+                0x4 => AddReg,
+                0x5 => Sub,
+                0x6 => Shr,
+                0x7 => Subn,
+                0xE => Shl,
+                _ => return None,
+            },
+            0x9 => SneReg,
+            0xA => LdI,
+            0xB => JpV0,
+            0xC => Rnd,
+            0xD => Drw,
+            0xE => match rest & 0x0FF {
+                0x9E => Skp,
+                0xA1 => Sknp,
+                _ => return None,
+            },
+            _ => match rest & 0x0FF {
+                0x00 if rest == 0x000 => LdILong,
+                0x01 => Plane,
+                0x02 => Audio,
+                0x07 => LdVxDt,
+                0x0A => LdVxK,
+                0x15 => LdDtVx,
+                0x18 => LdStVx,
+                0x1E => AddI,
+                0x29 => LdF,
+                0x30 => LdHf,
+                0x33 => LdB,
+                0x3A => Pitch,
+                0x55 => StoreRegs,
+                0x65 => LoadRegs,
+                0x75 => StoreFlags,
+                0x85 => LoadFlags,
+                _ => return None,
+            },
+        })
+    }
+
+    // Like of, but for a core running with `quirks`
+    pub fn with_quirks(opcode: u16, quirks: Quirks) -> Option<Self> {
+        Self::of(opcode).filter(|kind| quirks.xo_chip || !kind.needs_xo_chip())
+    }
+
+    // The kinds decode_and_exec only runs under Quirks::xo_chip. 00DN isn't one of them,
+    // scrolling up can't be mistaken for anything else.
+    fn needs_xo_chip(self) -> bool {
+        use OpcodeKind::*;
+        matches!(self, LdILong | Plane | Audio | Pitch)
+    }
+
+    pub fn platform(self) -> Platform {
+        use OpcodeKind::*;
+        match self {
+            ScrollDown | ScrollRight | ScrollLeft | Exit | Low | High | LdHf | StoreFlags | LoadFlags => Platform::SuperChip,
+            ScrollUp | LdILong | Plane | Audio | Pitch => Platform::XoChip,
+            _ => Platform::Chip8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+    use crate::{Chip8Error, Core, Quirks};
+
+    #[test]
+    fn lists_the_base_chip8_set() {
+        let base: Vec<OpcodeKind> = implemented_opcodes()
+            .iter()
+            .copied()
+            .filter(|kind| kind.platform() == Platform::Chip8)
+            .collect();
+        // The 35 of Cowgod's reference, with 0NNN as a no-op on 0000 only
+        assert_eq!(base.len(), 35);
+        for opcode in [0x00E0, 0x00EE, 0x1234, 0x8AB4, 0xD125, 0xE3A1, 0xF20A, 0xF565] {
+            assert!(base.contains(&OpcodeKind::of(opcode).unwrap()), "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn agrees_with_the_decoder() {
+        let mut core = Core::new(&[], false);
+        for opcode in 0..=0xFFFF {
+            core.reset();
+            let [high, low] = u16::to_be_bytes(opcode);
+            core.write_byte(0x200, high);
+            core.write_byte(0x201, low);
+            let invalid = core.cycle(&[]) == Err(Chip8Error::InvalidOpcode(opcode));
+
+            let kind = OpcodeKind::of(opcode);
+            assert_eq!(kind.is_none(), invalid, "{:04X}", opcode);
+            assert_eq!(kind.is_none(), disassemble(opcode).starts_with("UNKNOWN"), "{:04X}", opcode);
+            assert!(kind.is_none_or(|kind| implemented_opcodes().contains(&kind)));
+        }
+    }
+
+    #[test]
+    fn xo_chip_kinds_need_the_quirk() {
+        let quirks = Quirks { xo_chip: false, ..Quirks::default() };
+        let mut core = Core::with_quirks(&[], quirks);
+        for opcode in 0..=0xFFFF {
+            core.reset();
+            let [high, low] = u16::to_be_bytes(opcode);
+            core.write_byte(0x200, high);
+            core.write_byte(0x201, low);
+            let invalid = core.cycle(&[]) == Err(Chip8Error::InvalidOpcode(opcode));
+            assert_eq!(OpcodeKind::with_quirks(opcode, quirks).is_none(), invalid, "{:04X}", opcode);
+        }
+        for opcode in [0xF000, 0xF201, 0xF002, 0xF33A] {
+            assert!(OpcodeKind::of(opcode).is_some() && OpcodeKind::with_quirks(opcode, quirks).is_none(), "{:04X}", opcode);
+            assert_eq!(OpcodeKind::with_quirks(opcode, Quirks::default()), OpcodeKind::of(opcode));
+        }
+        // 00DN scrolls up either way
+        assert_eq!(OpcodeKind::with_quirks(0x00D4, quirks), Some(OpcodeKind::ScrollUp));
+    }
+}
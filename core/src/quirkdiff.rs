@@ -0,0 +1,103 @@
+// Runs one ROM under two quirk settings side by side to find which quirks it's sensitive
+// to. Both cores get the same CXNN seed and no keys, and are stepped one cycle at a time
+// so the first instruction that makes their screens differ can be pinned down.
+
+use crate::{frame_cycles, Chip8Error, Core, CoreBuilder, Quirks, HIRES_HEIGHT, HIRES_WIDTH};
+
+// Where the two screens first stopped matching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuirkDiff {
+    // 60Hz frame the difference showed up in, from 0
+    pub frame: usize,
+    // Cycle slot since the start, from 0. Under display_wait a core sits out the rest of
+    // a frame after drawing, so this can be ahead of one core's instruction count.
+    pub cycle: u64,
+    // pc of the instruction each core ran in that slot, None for one that was waiting
+    pub pcs: (Option<u16>, Option<u16>),
+    // Pixels that differ, over both planes
+    pub pixels: u32,
+}
+
+// Runs `frames` frames at `cps` with one timer tick after each, like Core::run_frames.
+// Returns None if the screens matched throughout. An error from either core ends the
+// run, as does both of them halting.
+pub fn diff_quirks(program: &[u8], quirks: (Quirks, Quirks), seed: u64, cps: usize, frames: usize) -> Result<Option<QuirkDiff>, Chip8Error> {
+    let build = |quirks| CoreBuilder::new().program(program).quirks(quirks).seed(seed).build();
+    let mut cores = [build(quirks.0)?, build(quirks.1)?];
+    let mut cycle = 0;
+    for frame in 0..frames {
+        // Cores that hit a display-wait draw this frame
+        let mut waiting = [false; 2];
+        for _ in 0..frame_cycles(cps, frame) {
+            let mut pcs = [None; 2];
+            for ((core, waiting), pc) in cores.iter_mut().zip(&mut waiting).zip(&mut pcs) {
+                if !*waiting {
+                    *pc = Some(core.pc());
+                    core.cycle(&[])?;
+                    *waiting = core.take_draw_pending();
+                }
+            }
+            let pixels = differing_pixels(&cores[0], &cores[1]);
+            if pixels > 0 {
+                return Ok(Some(QuirkDiff { frame, cycle, pcs: (pcs[0], pcs[1]), pixels }));
+            }
+            if cores.iter().all(Core::is_halted) {
+                return Ok(None);
+            }
+            cycle += 1;
+        }
+        for core in cores.iter_mut() {
+            core.tick_timers(1);
+        }
+    }
+    Ok(None)
+}
+
+// A resolution mismatch counts as the whole hires screen differing
+fn differing_pixels(a: &Core, b: &Core) -> u32 {
+    if a.hires != b.hires {
+        return (HIRES_WIDTH * HIRES_HEIGHT) as u32;
+    }
+    let planes = [(&a.display, &b.display), (&a.second_plane, &b.second_plane)];
+    planes
+        .iter()
+        .flat_map(|(a, b)| a.iter().zip(b.iter()))
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::*;
+
+    #[test]
+    fn finds_the_shift_quirk() {
+        // V0 = 1 and V1 = 4, so 8016 leaves V0 at 0 normally but 2 when shifting VY.
+        // The draw puts the font's 0 at V0, so only x differs.
+        let program = to_bytes(&[
+            ld_vx_byte(0, 1), ld_vx_byte(1, 4), ld_i(0x50), shr(0, 1), drw(0, 2, 5), jp(0x20A),
+        ]);
+        let modern = Quirks::default();
+        let shifting = Quirks { shift_uses_vy: true, ..modern };
+
+        let diff = diff_quirks(&program, (modern, shifting), 1, 600, 2).unwrap().unwrap();
+        assert_eq!((diff.frame, diff.cycle), (0, 4));
+        assert_eq!(diff.pcs, (Some(0x208), Some(0x208)));
+        assert!(diff.pixels > 0);
+
+        // The jump quirk never comes into play here
+        let jumping = Quirks { jump_offset_uses_vx: true, ..modern };
+        assert_eq!(diff_quirks(&program, (modern, jumping), 1, 600, 2), Ok(None));
+    }
+
+    #[test]
+    fn same_seed_keeps_random_draws_equal() {
+        let program = to_bytes(&[rnd(0, 0x3F), rnd(1, 0x1F), ld_i(0x50), drw(0, 1, 5), jp(0x200)]);
+        let diff = diff_quirks(&program, (Quirks::default(), Quirks::default()), 7, 600, 5).unwrap();
+        assert_eq!(diff, None);
+        // Wrapped sprites come out different, once one lands on an edge
+        let wrapping = Quirks { sprite_wrapping: true, ..Quirks::default() };
+        assert!(diff_quirks(&program, (Quirks::default(), wrapping), 7, 600, 60).unwrap().is_some());
+    }
+}
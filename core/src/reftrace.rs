@@ -0,0 +1,179 @@
+// Lockstep comparison against another emulator's per-instruction trace.
+//
+// Trace format: one line per instruction, giving the state just before it runs, the first
+// line being the state at power-on. Each line is whitespace separated hex, with or without
+// a 0x prefix:
+//   PC I V0 V1 V2 V3 V4 V5 V6 V7 V8 V9 VA VB VC VD VE VF
+// e.g. "0200 0000 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00". Anything after VF
+// (an opcode or disassembly) is ignored, as are blank lines and lines starting with #.
+// TraceState's Display writes the same layout, so this core can record a reference too.
+
+use std::fmt;
+
+use crate::{Chip8Error, Core, TraceEntry, NUM_REG};
+
+// Registers compared on each line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceState {
+    pub pc: u16,
+    pub i: u16,
+    pub v: [u8; NUM_REG],
+}
+
+impl TraceState {
+    pub fn of(core: &Core) -> Self {
+        Self { pc: core.pc(), i: core.i(), v: *core.registers() }
+    }
+}
+
+impl fmt::Display for TraceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X} {:04X}", self.pc, self.i)?;
+        for reg in &self.v {
+            write!(f, " {:02X}", reg)?;
+        }
+        Ok(())
+    }
+}
+
+// A trace line that couldn't be read, `line` counting from 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceParseError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for TraceParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "trace line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for TraceParseError {}
+
+pub fn parse_trace(text: &str) -> Result<Vec<TraceState>, TraceParseError> {
+    let mut states = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |reason: String| TraceParseError { line: index + 1, reason };
+        let fields: Vec<&str> = line.split_whitespace().take(2 + NUM_REG).collect();
+        if fields.len() < 2 + NUM_REG {
+            return Err(error(format!("expected {} fields, found {}", 2 + NUM_REG, fields.len())));
+        }
+        let hex = |field: &str| {
+            let digits = field.strip_prefix("0x").or_else(|| field.strip_prefix("0X")).unwrap_or(field);
+            u16::from_str_radix(digits, 16).map_err(|_| error(format!("{:?} isn't hex", field)))
+        };
+        let mut v = [0; NUM_REG];
+        for (reg, field) in v.iter_mut().zip(&fields[2..]) {
+            *reg = u8::try_from(hex(field)?).map_err(|_| error(format!("{} doesn't fit a register", field)))?;
+        }
+        states.push(TraceState { pc: hex(fields[0])?, i: hex(fields[1])?, v });
+    }
+    Ok(states)
+}
+
+// First point where the core disagreed with the reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // Instructions executed before the mismatch, i.e. the index of the trace line
+    pub cycle: usize,
+    // The instruction that produced the mismatched state, None if it differed at power-on
+    pub last: Option<TraceEntry>,
+    pub expected: TraceState,
+    pub actual: TraceState,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "diverged at cycle {}", self.cycle)?;
+        match self.last {
+            Some(entry) => write!(f, " after {:04X} {} at {:#06X}", entry.opcode, entry.mnemonic(), entry.pc)?,
+            None => write!(f, " before the first instruction")?,
+        }
+        write!(f, "\n         PC   I    V0 V1 V2 V3 V4 V5 V6 V7 V8 V9 VA VB VC VD VE VF")?;
+        write!(f, "\nexpected {}\nactual   {}", self.expected, self.actual)
+    }
+}
+
+// Runs `core` one instruction per reference line with `keys` held, returning the first
+// line it disagrees with or None if it matched all of them. An error from the core ends
+// the run, the reference can't say what state an error should leave behind.
+pub fn compare(core: &mut Core, reference: &[TraceState], keys: &[u8]) -> Result<Option<Divergence>, Chip8Error> {
+    let mut last = None;
+    for (cycle, &expected) in reference.iter().enumerate() {
+        let actual = TraceState::of(core);
+        if actual != expected {
+            return Ok(Some(Divergence { cycle, last, expected, actual }));
+        }
+        // The last line only has its state checked, there's nothing after it to compare
+        if cycle + 1 < reference.len() {
+            let pc = core.pc();
+            let opcode = u16::from_be_bytes([core.read_byte(pc), core.read_byte(pc.wrapping_add(1))]);
+            last = Some(TraceEntry { pc, opcode });
+            core.step(keys)?;
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::*;
+
+    fn program() -> Vec<u8> {
+        to_bytes(&[ld_vx_byte(0, 0x05), add_vx_byte(0, 0x03), ld_i(0x300), jp(0x206)])
+    }
+
+    // Records this core's own trace of `cycles` instructions in the file format
+    fn record(cycles: usize) -> String {
+        let mut core = Core::new(&program(), false);
+        let mut text = String::from("# PC I V0..VF\n");
+        for _ in 0..=cycles {
+            let pc = core.pc();
+            let opcode = (core.read_byte(pc), core.read_byte(pc + 1));
+            text.push_str(&format!("{} {:02X}{:02X}\n", TraceState::of(&core), opcode.0, opcode.1));
+            core.step(&[]).unwrap();
+        }
+        text
+    }
+
+    #[test]
+    fn matching_trace_has_no_divergence() {
+        let reference = parse_trace(&record(6)).unwrap();
+        assert_eq!(reference.len(), 7);
+        let mut core = Core::new(&program(), false);
+        assert_eq!(compare(&mut core, &reference, &[]), Ok(None));
+    }
+
+    #[test]
+    fn reports_first_divergent_cycle_and_opcode() {
+        let mut reference = parse_trace(&record(4)).unwrap();
+        // The reference says 7003 left V0 at 9, one more than ours
+        reference[2].v[0] = 0x09;
+        let mut core = Core::new(&program(), false);
+        let divergence = compare(&mut core, &reference, &[]).unwrap().unwrap();
+        assert_eq!(divergence.cycle, 2);
+        assert_eq!(divergence.last, Some(TraceEntry { pc: 0x202, opcode: 0x7003 }));
+        assert_eq!(divergence.expected.v[0], 0x09);
+        assert_eq!(divergence.actual.v[0], 0x08);
+        assert!(divergence.to_string().contains("cycle 2 after 7003"));
+    }
+
+    #[test]
+    fn parses_prefixed_hex_and_rejects_short_lines() {
+        let line = "0x0200 0x0300 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F 10 6001 LD V0, 0x01";
+        let states = parse_trace(&format!("\n# header\n{}\n", line)).unwrap();
+        assert_eq!(states[0].pc, 0x200);
+        assert_eq!(states[0].i, 0x300);
+        assert_eq!(states[0].v[0xF], 0x10);
+
+        let err = parse_trace("0200 0000 00\n").unwrap_err();
+        assert_eq!(err.line, 1);
+        assert!(parse_trace("0200 0000 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 100").is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{Chip8Error, Core};
+
+// Timers and frames both run at 60Hz on the emulator thread
+const FRAME_PERIOD: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+// Messages sent from the UI thread to the emulator thread
+pub enum Command {
+    // Replace the currently held keys (CHIP-8 key values 0x0-0xF)
+    Keys(Vec<u8>),
+    Pause,
+    Resume,
+    // Restart the current program from scratch
+    Reset,
+    // Swap in a new program and start it from scratch. One that's too large is reported
+    // as an Error and the current program stays loaded.
+    Load(Vec<u8>),
+    // Execute a single instruction, mostly useful while paused
+    Step,
+    Shutdown,
+}
+
+// Messages sent from the emulator thread back to the UI thread
+pub enum Event {
+    // Copy of the active display area after a frame (or a step) completed, 64x32 or
+    // 128x64 going by its length
+    Frame(Vec<bool>),
+    // Sound timer went from zero to non-zero, or back. Also sent on Pause and Resume.
+    Sound(bool),
+    // The core hit an error; the emulator pauses until told to resume, reset or load
+    Error(Chip8Error),
+    // Emulator thread is exiting, either from Shutdown or a dropped sender
+    Stopped,
+}
+
+struct Emulator {
+    core: Core,
+    keys: Vec<u8>,
+    paused: bool,
+    sound: bool,
+    events: Sender<Event>,
+}
+
+impl Emulator {
+    // Returns false when the thread should exit
+    fn handle(&mut self, command: Command) -> bool {
+        match command {
+            Command::Keys(keys) => self.keys = keys,
+            // The tone stops with the timers while paused, and picks back up on resume if
+            // the sound timer still has time left
+            Command::Pause => {
+                self.paused = true;
+                self.set_sound(false);
+            }
+            Command::Resume => {
+                self.paused = false;
+                self.set_sound(self.core.is_sound_active());
+            }
+            // A fresh start clears a pause from an earlier error
+            Command::Reset => {
+                self.core.reset();
+                self.paused = false;
+            }
+            Command::Load(program) => {
+                let result = self.core.reset_and_reload(&program);
+                self.paused = false;
+                self.check(result);
+            }
+            Command::Step => {
+                let result = self.core.cycle(&self.keys);
+                self.check(result);
+                self.send_frame();
+            }
+            Command::Shutdown => return false,
+        }
+        true
+    }
+
+    fn run_frame(&mut self, cycles_per_frame: usize) {
+        let result = self.core.run_frame(cycles_per_frame, &self.keys);
+        self.check(result);
+        self.core.decrement_timers();
+
+        self.set_sound(self.core.is_sound_active());
+        self.send_frame();
+    }
+
+    // Sends a Sound event only when the state actually changes
+    fn set_sound(&mut self, sound: bool) {
+        if sound != self.sound {
+            self.sound = sound;
+            let _ = self.events.send(Event::Sound(sound));
+        }
+    }
+
+    fn check<T>(&mut self, result: Result<T, Chip8Error>) {
+        if let Err(err) = result {
+            self.paused = true;
+            let _ = self.events.send(Event::Error(err));
+        }
+    }
+
+    fn send_frame(&self) {
+        let (width, height) = self.core.display_dimensions();
+        let frame = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| self.core.pixel(x, y))
+            .collect();
+        let _ = self.events.send(Event::Frame(frame));
+    }
+}
+
+// Runs a Core on its own thread, driven by Commands and reporting back Events.
+// The thread exits on Command::Shutdown or once the command sender is dropped.
+pub fn spawn_emulator(
+    program: Vec<u8>,
+    legacy: bool,
+    cycles_per_frame: usize,
+) -> (Sender<Command>, Receiver<Event>, JoinHandle<()>) {
+    let (command_tx, command_rx) = mpsc::channel();
+    let (event_tx, event_rx) = mpsc::channel();
+
+    let handle = thread::spawn(move || {
+        // A program that doesn't fit is reported and the thread stops straight away
+        let core = match Core::try_new(&program, legacy) {
+            Ok(core) => core,
+            Err(err) => {
+                let _ = event_tx.send(Event::Error(err));
+                let _ = event_tx.send(Event::Stopped);
+                return;
+            }
+        };
+        let mut emu = Emulator {
+            core,
+            keys: Vec::new(),
+            paused: false,
+            sound: false,
+            events: event_tx,
+        };
+        run(&mut emu, &command_rx, cycles_per_frame);
+        let _ = emu.events.send(Event::Stopped);
+    });
+
+    (command_tx, event_rx, handle)
+}
+
+fn run(emu: &mut Emulator, commands: &Receiver<Command>, cycles_per_frame: usize) {
+    loop {
+        let frame_start = Instant::now();
+
+        // While paused there's nothing to do but wait for the next command
+        if emu.paused {
+            match commands.recv() {
+                Ok(command) => if !emu.handle(command) { return },
+                Err(_) => return,
+            }
+            continue;
+        }
+
+        loop {
+            match commands.try_recv() {
+                Ok(command) => if !emu.handle(command) { return },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        if emu.paused {
+            continue;
+        }
+
+        emu.run_frame(cycles_per_frame);
+
+        let elapsed = frame_start.elapsed();
+        if elapsed < FRAME_PERIOD {
+            thread::sleep(FRAME_PERIOD - elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::*;
+
+    #[test]
+    fn load_runs_and_sends_frames() {
+        let (commands, events, handle) = spawn_emulator(Vec::new(), false, 10);
+        // Draws the font's 0 at the top left then spins
+        let program = to_bytes(&[ld_i(0x50), drw(0, 0, 5), jp(0x204)]);
+        commands.send(Command::Load(program)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let lit = loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match events.recv_timeout(timeout).expect("no frame with the sprite drawn") {
+                Event::Frame(pixels) if pixels[0] => break pixels,
+                Event::Error(err) => panic!("unexpected error {}", err),
+                _ => {}
+            }
+        };
+        assert_eq!(lit.len(), 64 * 32);
+        // Top row of the 0 glyph is 0xF0
+        assert_eq!(&lit[..5], &[true, true, true, true, false]);
+
+        commands.send(Command::Shutdown).unwrap();
+        handle.join().unwrap();
+        assert!(events.iter().any(|event| matches!(event, Event::Stopped)));
+    }
+
+    // Waits for the next Error, skipping frames and sound changes
+    fn next_error(events: &Receiver<Event>) -> Chip8Error {
+        loop {
+            match events.recv_timeout(Duration::from_secs(5)).expect("no error reported") {
+                Event::Error(err) => return err,
+                Event::Stopped => panic!("stopped before reporting an error"),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn errors_pause_until_reset() {
+        let (commands, events, handle) = spawn_emulator(to_bytes(&[0xE000]), false, 10);
+        assert_eq!(next_error(&events), Chip8Error::InvalidOpcode(0xE000));
+
+        // Reset unpauses, so the same opcode is hit again
+        commands.send(Command::Reset).unwrap();
+        assert_eq!(next_error(&events), Chip8Error::InvalidOpcode(0xE000));
+
+        // As does loading a new program
+        commands.send(Command::Load(to_bytes(&[ret()]))).unwrap();
+        assert_eq!(next_error(&events), Chip8Error::StackUnderflow);
+
+        commands.send(Command::Shutdown).unwrap();
+        handle.join().unwrap();
+    }
+
+    // Waits for the next Sound change, skipping frames
+    fn next_sound(events: &Receiver<Event>) -> bool {
+        loop {
+            match events.recv_timeout(Duration::from_secs(5)).expect("no sound change") {
+                Event::Sound(on) => return on,
+                Event::Error(err) => panic!("unexpected error {}", err),
+                Event::Stopped => panic!("stopped before the sound changed"),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn pause_silences_a_beep_until_resume() {
+        // Starts a beep of a little over four seconds, then spins
+        let program = to_bytes(&[ld_vx_byte(0, 0xFF), ld_st_vx(0), jp(0x204)]);
+        let (commands, events, handle) = spawn_emulator(program, false, 10);
+        assert!(next_sound(&events));
+
+        commands.send(Command::Pause).unwrap();
+        assert!(!next_sound(&events));
+        // The sound timer doesn't run down while paused, so the beep comes back
+        commands.send(Command::Resume).unwrap();
+        assert!(next_sound(&events));
+
+        commands.send(Command::Shutdown).unwrap();
+        handle.join().unwrap();
+    }
+}
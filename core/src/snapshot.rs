@@ -0,0 +1,231 @@
+use crate::{Chip8Error, Core, CoreState, CpuState, Plane, Quirks};
+use crate::{AUDIO_BUFFER_SIZE, HIRES_HEIGHT, HIRES_WIDTH, MAX_STACK, NUM_REG, RAM_SIZE};
+
+// Binary save states: the magic and a version byte, then the CoreState fields in
+// declaration order. Multi-byte values are little-endian, which makes each display row
+// 16 bytes with column 0 in the low bit of the first. Version 1 stored the planes as
+// flat pixel arrays, version 2 had no i_mask_12bit or xo_chip quirk bits and version 3
+// no RNG state, none of them are readable any more.
+const MAGIC: &[u8; 4] = b"C8SS";
+const VERSION: u8 = 4;
+
+impl Core {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let state = self.save_state();
+        let mut out = Vec::with_capacity(RAM_SIZE + 2 * HIRES_WIDTH * HIRES_HEIGHT / 8 + 128);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+
+        let cpu = &state.cpu;
+        out.extend_from_slice(&cpu.pc.to_le_bytes());
+        out.extend_from_slice(&cpu.i_reg.to_le_bytes());
+        out.extend_from_slice(&cpu.v_reg);
+        out.push(cpu.d_timer);
+        out.push(cpu.s_timer);
+        for addr in cpu.stack {
+            out.extend_from_slice(&addr.to_le_bytes());
+        }
+        out.push(cpu.sp as u8);
+
+        out.extend_from_slice(&state.ram[..]);
+        pack_plane(&state.display, &mut out);
+        pack_plane(&state.second_plane, &mut out);
+        out.push(state.selected_planes);
+        out.push(state.hires as u8);
+        out.push(quirk_bits(state.quirks));
+        out.push(state.halted as u8);
+        out.extend_from_slice(&state.rpl_flags);
+        out.extend_from_slice(&state.audio_buffer);
+        out.push(state.audio_pitch);
+        out.extend_from_slice(&state.instruction_count.to_le_bytes());
+        out.extend_from_slice(&state.rng_state.to_le_bytes());
+        out
+    }
+
+    // The rebuilt Core has default settings like Core::new, only the machine state comes
+    // from the snapshot
+    pub fn from_bytes(data: &[u8]) -> Result<Core, Chip8Error> {
+        let mut reader = Reader { data };
+        if reader.take(MAGIC.len())? != MAGIC || reader.byte()? != VERSION {
+            return Err(Chip8Error::BadSnapshot);
+        }
+
+        let pc = reader.u16()?;
+        let i_reg = reader.u16()?;
+        let v_reg: [u8; NUM_REG] = reader.array()?;
+        let d_timer = reader.byte()?;
+        let s_timer = reader.byte()?;
+        let mut stack = [0; MAX_STACK];
+        for addr in stack.iter_mut() {
+            *addr = reader.u16()?;
+        }
+        let sp = reader.byte()? as usize;
+        let cpu = CpuState { pc, i_reg, v_reg, d_timer, s_timer, stack, sp };
+
+        let mut ram = Box::new([0; RAM_SIZE]);
+        ram.copy_from_slice(reader.take(RAM_SIZE)?);
+        let display = reader.plane()?;
+        let second_plane = reader.plane()?;
+        let selected_planes = reader.byte()?;
+        let hires = reader.flag()?;
+        let quirks = quirks_from_bits(reader.byte()?)?;
+        let halted = reader.flag()?;
+        let rpl_flags = reader.array()?;
+        let audio_buffer: [u8; AUDIO_BUFFER_SIZE] = reader.array()?;
+        let audio_pitch = reader.byte()?;
+        let instruction_count = u64::from_le_bytes(reader.array()?);
+        let rng_state = u64::from_le_bytes(reader.array()?);
+        if !reader.data.is_empty() {
+            return Err(Chip8Error::BadSnapshot);
+        }
+
+        // load_state checks the values themselves: stack pointer, plane mask and generator
+        let mut core = Core::with_quirks(&[], quirks);
+        core.load_state(CoreState {
+            cpu,
+            ram,
+            display,
+            second_plane,
+            selected_planes,
+            hires,
+            quirks,
+            halted,
+            rpl_flags,
+            audio_buffer,
+            audio_pitch,
+            instruction_count,
+            rng_state,
+        })?;
+        Ok(core)
+    }
+}
+
+fn pack_plane(plane: &Plane, out: &mut Vec<u8>) {
+    for row in plane {
+        out.extend_from_slice(&row.to_le_bytes());
+    }
+}
+
+fn quirk_bits(quirks: Quirks) -> u8 {
+    [
+        quirks.shift_uses_vy,
+        quirks.load_store_increments_i,
+        quirks.jump_offset_uses_vx,
+        quirks.logic_resets_vf,
+        quirks.sprite_wrapping,
+        quirks.display_wait,
+        quirks.i_mask_12bit,
+        quirks.xo_chip,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (i, &on)| bits | (on as u8) << i)
+}
+
+// All 8 bits are in use. A combination Quirks::validate rejects is a bad snapshot.
+fn quirks_from_bits(bits: u8) -> Result<Quirks, Chip8Error> {
+    let on = |i: u8| bits & (1 << i) != 0;
+    let quirks = Quirks {
+        shift_uses_vy: on(0),
+        load_store_increments_i: on(1),
+        jump_offset_uses_vx: on(2),
+        logic_resets_vf: on(3),
+        sprite_wrapping: on(4),
+        display_wait: on(5),
+        i_mask_12bit: on(6),
+        xo_chip: on(7),
+    };
+    quirks.validate().map_err(|_| Chip8Error::BadSnapshot)?;
+    Ok(quirks)
+}
+
+// Reads fields off the front of a snapshot, any shortfall is a BadSnapshot
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Chip8Error> {
+        if self.data.len() < len {
+            return Err(Chip8Error::BadSnapshot);
+        }
+        let (field, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(field)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Chip8Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn byte(&mut self) -> Result<u8, Chip8Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Chip8Error> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn flag(&mut self) -> Result<bool, Chip8Error> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Chip8Error::BadSnapshot),
+        }
+    }
+
+    fn plane(&mut self) -> Result<Plane, Chip8Error> {
+        let mut plane = [0; HIRES_HEIGHT];
+        for row in plane.iter_mut() {
+            *row = u128::from_le_bytes(self.array()?);
+        }
+        Ok(plane)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::*;
+
+    fn running_core() -> Core {
+        let program = to_bytes(&[0x00FF, ld_vx_byte(0, 0x30), ld_i(0x50), drw(0, 0, 5), call(0x20C), jp(0x20A), ld_st_vx(0), ret()]);
+        let mut core = Core::with_quirks(&program, Quirks::cosmac_vip());
+        for _ in 0..6 {
+            core.cycle(&[]).unwrap();
+        }
+        core
+    }
+
+    #[test]
+    fn round_trips_the_machine_state() {
+        let core = running_core();
+        let bytes = core.to_bytes();
+        assert_eq!(&bytes[..5], b"C8SS\x04");
+        let mut restored = Core::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.save_state(), core.save_state());
+        assert_eq!(restored.to_bytes(), bytes);
+        // Carries on from the ret with the stack intact
+        restored.cycle(&[]).unwrap();
+        assert_eq!(restored.pc(), 0x20A);
+    }
+
+    #[test]
+    fn rejects_truncated_and_mismatched_snapshots() {
+        let bytes = running_core().to_bytes();
+        for len in [0, 3, 5, 40, bytes.len() - 1] {
+            assert_eq!(Core::from_bytes(&bytes[..len]).err(), Some(Chip8Error::BadSnapshot), "length {}", len);
+        }
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(Core::from_bytes(&extra).err(), Some(Chip8Error::BadSnapshot));
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert_eq!(Core::from_bytes(&magic).err(), Some(Chip8Error::BadSnapshot));
+        let mut version = bytes;
+        version[4] = 3;
+        assert_eq!(Core::from_bytes(&version).err(), Some(Chip8Error::BadSnapshot));
+    }
+}
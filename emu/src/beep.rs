@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::wav::SAMPLE_RATE;
+
+// Gain ramps over about 2ms when the beep starts or stops, cutting the square wave off
+// mid-period is what makes the click
+const RAMP_SAMPLES: f32 = 88.0;
+
+// Endless square wave for rodio that's only audible while `gate` is set. The sink keeps
+// playing it the whole time and the core's sound callback flips the gate.
+pub struct Beep {
+    gate: Arc<AtomicBool>,
+    // samples per square wave period
+    period: f32,
+    volume: f32,
+    // position within the current period, in samples
+    phase: f32,
+    // 0.0 silent to 1.0 full volume, moves towards the gate a step per sample
+    gain: f32,
+}
+
+impl Beep {
+    pub fn new(gate: Arc<AtomicBool>, freq: f32, volume: f32) -> Self {
+        Self {
+            gate,
+            period: SAMPLE_RATE as f32 / freq,
+            volume,
+            phase: 0.0,
+            gain: 0.0,
+        }
+    }
+}
+
+impl Iterator for Beep {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let target = if self.gate.load(Ordering::Relaxed) { 1.0 } else { 0.0 };
+        let step = 1.0 / RAMP_SAMPLES;
+        self.gain = if self.gain < target {
+            (self.gain + step).min(target)
+        } else {
+            (self.gain - step).max(target)
+        };
+
+        let level = if self.phase < self.period / 2.0 { 1.0 } else { -1.0 };
+        self.phase = (self.phase + 1.0) % self.period;
+        Some(level * self.volume * self.gain)
+    }
+}
+
+impl Source for Beep {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
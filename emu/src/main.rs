@@ -1,26 +1,166 @@
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::env;
 use std::fs;
-use std::thread;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use minifb::{Key, Window, WindowOptions};
 use rodio::{OutputStream, Sink};
-use rodio::source::{SineWave, Source};
-use core::Core;
+use core::opcodes::{implemented_opcodes, OpcodeKind, Platform};
+use core::{batch, disasm, frame_cycles, quirkdiff, reftrace, Chip8Error, Core, Quirks, SCREEN_HEIGHT, SCREEN_WIDTH};
 
-const WIDTH: usize = 640;
-const HEIGHT: usize = 320;
+mod beep;
+mod wav;
+use beep::Beep;
+use wav::WavRecorder;
+
+// Output pixels per lores CHIP-8 pixel, a 640x320 window by default. Override with --scale.
+const DEFAULT_SCALE: usize = 10;
+const MAX_SCALE: usize = 40;
 
 const FPS: usize = 60;
+// Default instructions per second, override with --cps
 const CPS: usize = 660;
 
 const TIMER_FREQUENCY: u64 = 60;
 const TIMER_PERIOD: Duration = Duration::from_nanos((1_000_000_000) / TIMER_FREQUENCY);
 
+// Square wave beep while the sound timer runs, also used for --wav recordings
 const AUDIO_FREQ: f32 = 440.0;
 const AUDIO_VOL: f32 = 0.2;
 
+// Indexed by XO-CHIP color, off and on for plain CHIP-8 then the second plane on its
+// own and both planes together
+const PALETTE: [u32; 4] = [0x00000000, 0xFFFFFFFF, 0xFFFF6600, 0xFFFFCC00];
+
+// Outline color for the F1 sprite debug overlay
+const DRAW_RECT_COLOR: u32 = 0xFFFF0000;
+
+// How much scanline rows are darkened, as a percentage
+const DEFAULT_SCANLINE_INTENSITY: u8 = 50;
+
+struct RenderOptions {
+    // Percentage every other output row is darkened by, None to disable
+    scanlines: Option<u8>,
+    // Draw each CHIP-8 pixel as a rounded LCD-style dot instead of a hard square
+    dots: bool,
+    // Stretch to a 4:3 picture with non-square pixels
+    aspect: bool,
+    // Output pixels per lores pixel, from --scale
+    scale: usize,
+}
+
+impl RenderOptions {
+    // Output pixels covered by one CHIP-8 pixel, as (width, height), for a display of
+    // the given dimensions
+    fn pixel_size(&self, dims: (usize, usize)) -> (usize, usize) {
+        let width = SCREEN_WIDTH * self.scale;
+        // With --aspect pixels are 1.5x taller, stretching the 2:1 screen to 4:3 like a CRT
+        let height = SCREEN_HEIGHT * self.scale * if self.aspect { 3 } else { 2 } / 2;
+        // Hires at --scale 1 would round to nothing, so its pixels stay at least 1 wide
+        // and minifb shrinks the bigger buffer to fit the window
+        ((width / dims.0).max(1), (height / dims.1).max(1))
+    }
+
+    fn buffer_size(&self, dims: (usize, usize)) -> (usize, usize) {
+        let (pixel_w, pixel_h) = self.pixel_size(dims);
+        (dims.0 * pixel_w, dims.1 * pixel_h)
+    }
+}
+
+// After the first report, repeats of the same invalid op are summarised every this many hits
+const INVALID_OP_SUMMARY_EVERY: u64 = 1000;
+
+// Logs each invalid opcode the first time it's hit at a given address, then only a
+// periodic count of suppressed repeats so a ROM stuck on garbage doesn't flood stderr
+struct InvalidOpLog {
+    // keyed by (pc, opcode)
+    hits: HashMap<(u16, u16), u64>,
+}
+
+impl InvalidOpLog {
+    // Returns the line to log for this hit, if any
+    fn report(&mut self, pc: u16, opcode: u16) -> Option<String> {
+        let hits = self.hits.entry((pc, opcode)).or_insert(0);
+        *hits += 1;
+
+        if *hits == 1 {
+            Some(format!("invalid op {:#06X} at {:#06X}", opcode, pc))
+        } else if (*hits - 1).is_multiple_of(INVALID_OP_SUMMARY_EVERY) {
+            Some(format!("invalid op {:#06X} at {:#06X} (suppressed {})", opcode, pc, INVALID_OP_SUMMARY_EVERY))
+        } else {
+            None
+        }
+    }
+}
+
+// A frame counts as dropped once it takes this much longer than its budget
+const FRAME_DROP_FACTOR: f64 = 1.5;
+const TIMING_SUMMARY_PERIOD: Duration = Duration::from_secs(5);
+
+// Frame pacing measurements for --timing-log, reported to stderr
+struct FrameStats {
+    target: Duration,
+    last_frame: Instant,
+    last_summary: Instant,
+    frames: u32,
+    dropped: u32,
+    total: Duration,
+    max_jitter: Duration,
+}
+
+impl FrameStats {
+    fn new(fps: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            target: Duration::from_nanos(1_000_000_000 / fps as u64),
+            last_frame: now,
+            last_summary: now,
+            frames: 0,
+            dropped: 0,
+            total: Duration::ZERO,
+            max_jitter: Duration::ZERO,
+        }
+    }
+
+    // Call once per presented frame
+    fn record(&mut self) {
+        let now = Instant::now();
+        let frame_time = now - self.last_frame;
+        self.last_frame = now;
+
+        let jitter = frame_time.abs_diff(self.target);
+        self.max_jitter = self.max_jitter.max(jitter);
+        self.total += frame_time;
+        self.frames += 1;
+
+        if frame_time.as_secs_f64() > self.target.as_secs_f64() * FRAME_DROP_FACTOR {
+            self.dropped += 1;
+            eprintln!("frame drop: {:.2}ms (target {:.2}ms)", ms(frame_time), ms(self.target));
+        }
+
+        if now - self.last_summary >= TIMING_SUMMARY_PERIOD {
+            eprintln!(
+                "timing: {} frames, target {:.2}ms, avg {:.2}ms, max jitter {:.2}ms, {} dropped",
+                self.frames, ms(self.target), ms(self.total / self.frames), ms(self.max_jitter), self.dropped
+            );
+            self.last_summary = now;
+            self.frames = 0;
+            self.dropped = 0;
+            self.total = Duration::ZERO;
+            self.max_jitter = Duration::ZERO;
+        }
+    }
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
 fn get_program(args: &[String]) -> Result<Vec<u8>, Box<dyn Error>> {
     if args.len() < 2 {
         return Err("Not enough arguments".into());
@@ -29,15 +169,300 @@ fn get_program(args: &[String]) -> Result<Vec<u8>, Box<dyn Error>> {
     Ok(fs::read(file_path)?)
 }
 
-fn write_to_buffer(display: &[bool], buffer: &mut Vec<u32>) {
-    for (i, pixel) in buffer.iter_mut().enumerate() {
-        let (x, y) = (i % 640, i / 640);
-        let original_pixel = display[(64 * (y/10)) + (x/10)];
+// --vip starts from the COSMAC VIP preset, and each --quirk-* flag switches on a single
+// quirk on top of whichever base was picked. --legacy is the original flag, kept to mean
+// just the shift quirk.
+fn get_quirks(args: &[String]) -> Quirks {
+    let has = |flag: &str| args.iter().any(|arg| arg == flag);
+    let mut quirks = if has("--vip") { Quirks::cosmac_vip() } else { Quirks::default() };
+    quirks.shift_uses_vy |= has("--legacy") || has("--quirk-shift");
+    quirks.jump_offset_uses_vx |= has("--quirk-jump");
+    quirks.load_store_increments_i |= has("--quirk-memory");
+    quirks.logic_resets_vf |= has("--quirk-vf-reset");
+    quirks.display_wait |= has("--quirk-display-wait");
+    quirks.sprite_wrapping |= has("--quirk-wrap");
+    quirks
+}
+
+// --check lists which instruction sets the ROM uses and any words this build can't run
+// with the chosen quirks, going by a straight disassembly, so sprite data shows up as
+// instructions too. Returns false without the flag.
+fn run_check(args: &[String], program: &[u8], quirks: Quirks) -> bool {
+    if !args.iter().any(|arg| arg == "--check") {
+        return false;
+    }
+    let mut used: BTreeMap<Platform, usize> = BTreeMap::new();
+    let mut unsupported = Vec::new();
+    for (addr, opcode, mnemonic) in disasm::disassemble_rom(program, 0x200) {
+        match OpcodeKind::with_quirks(opcode, quirks).filter(|kind| implemented_opcodes().contains(kind)) {
+            Some(kind) => *used.entry(kind.platform()).or_insert(0) += 1,
+            None => unsupported.push((addr, mnemonic)),
+        }
+    }
+    for (platform, count) in used {
+        let known = implemented_opcodes().iter().filter(|kind| kind.platform() == platform).count();
+        println!("{:?}: {} instructions ({} kinds supported)", platform, count, known);
+    }
+    if unsupported.is_empty() {
+        println!("every word decodes to a supported instruction");
+    }
+    for (addr, mnemonic) in unsupported {
+        println!("{:#06X} unsupported: {}", addr, mnemonic);
+    }
+    true
+}
+
+// The quirk a --quirk-* flag or --diff-quirks name refers to
+fn quirk_flag<'a>(quirks: &'a mut Quirks, name: &str) -> Option<&'a mut bool> {
+    Some(match name {
+        "shift" => &mut quirks.shift_uses_vy,
+        "jump" => &mut quirks.jump_offset_uses_vx,
+        "memory" => &mut quirks.load_store_increments_i,
+        "vf-reset" => &mut quirks.logic_resets_vf,
+        "display-wait" => &mut quirks.display_wait,
+        "wrap" => &mut quirks.sprite_wrapping,
+        _ => return None,
+    })
+}
+
+// Length of a --diff-quirks run, 10 seconds
+const DIFF_FRAMES: usize = 600;
+// CXNN seed both --diff-quirks cores share
+const DIFF_SEED: u64 = 0;
 
-        *pixel = match original_pixel {
-            true => 0xFFFFFFFF,
-            false => 0x00000000
+// --diff-quirks a,b,... runs the ROM headless twice with no keys held, once with the
+// quirks picked by the other flags and once with the named ones (shift, jump, memory,
+// vf-reset, display-wait, wrap) flipped. Prints where the screens first differ. Returns
+// false without the flag.
+fn run_quirk_diff(args: &[String], program: &[u8], quirks: Quirks, cps: usize) -> Result<bool, Box<dyn Error>> {
+    let names = match args.iter().position(|arg| arg == "--diff-quirks") {
+        Some(i) => args.get(i + 1).ok_or("--diff-quirks needs a comma separated list of quirks")?,
+        None => return Ok(false),
+    };
+    let mut flipped = quirks;
+    for name in names.split(',') {
+        let flag = quirk_flag(&mut flipped, name).ok_or_else(|| format!("unknown quirk {}", name))?;
+        *flag = !*flag;
+    }
+    println!("A: {:?}\nB: {:?}", quirks, flipped);
+    match quirkdiff::diff_quirks(program, (quirks, flipped), DIFF_SEED, cps, DIFF_FRAMES) {
+        Ok(None) => println!("screens matched for all {} frames", DIFF_FRAMES),
+        Ok(Some(diff)) => {
+            let pc = |pc: Option<u16>| pc.map_or("waiting".to_string(), |pc| format!("{:#06X}", pc));
+            println!(
+                "screens first differ in frame {}, cycle {}: {} pixels, A ran {} and B ran {}",
+                diff.frame, diff.cycle, diff.pixels, pc(diff.pcs.0), pc(diff.pcs.1)
+            );
         }
+        Err(err) => println!("stopped on an error before the screens differed: {}", err),
+    }
+    Ok(true)
+}
+
+// --batch MANIFEST runs every ROM the manifest lists headless and checks the screen it
+// ends on (format in core::batch), for CI. ROM paths are relative to the manifest, and
+// each entry's quirks go on top of the ones picked by the other flags. Prints a line per
+// ROM and exits with status 1 if any failed. Returns false without the flag.
+fn run_batch(args: &[String]) -> Result<bool, Box<dyn Error>> {
+    let path = match args.iter().position(|arg| arg == "--batch") {
+        Some(i) => args.get(i + 1).ok_or("--batch needs a manifest file")?,
+        None => return Ok(false),
+    };
+    let manifest = batch::parse_manifest(&fs::read_to_string(path)?)?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    let cps = get_cps(args)?;
+    let mut failed = 0;
+    for entry in &manifest {
+        let mut quirks = get_quirks(args);
+        for name in &entry.quirks {
+            *quirk_flag(&mut quirks, name).ok_or_else(|| format!("{}: unknown quirk {}", entry.path, name))? = true;
+        }
+        let result = fs::read(dir.join(&entry.path))
+            .map_err(|err| err.to_string())
+            .and_then(|program| batch::run_entry(&program, quirks, cps, entry.frames).map_err(|err| err.to_string()));
+        match result {
+            Ok(hash) if hash == entry.hash => println!("PASS {}", entry.path),
+            Ok(hash) => {
+                failed += 1;
+                println!("FAIL {}: expected {:016x}, got {:016x}", entry.path, entry.hash, hash);
+            }
+            Err(err) => {
+                failed += 1;
+                println!("FAIL {}: {}", entry.path, err);
+            }
+        }
+    }
+    println!("{} of {} passed", manifest.len() - failed, manifest.len());
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(true)
+}
+
+// Accepts --wav followed by the output file path
+fn get_wav_path(args: &[String]) -> Result<Option<String>, Box<dyn Error>> {
+    match args.iter().position(|arg| arg == "--wav") {
+        Some(i) => match args.get(i + 1) {
+            Some(path) => Ok(Some(path.clone())),
+            None => Err("--wav needs an output file path".into()),
+        },
+        None => Ok(None),
+    }
+}
+
+// Accepts --scanlines (default intensity) or --scanlines=N with N a percentage from 0 to 100
+fn get_scanlines(args: &[String]) -> Result<Option<u8>, Box<dyn Error>> {
+    for arg in args {
+        if arg == "--scanlines" {
+            return Ok(Some(DEFAULT_SCANLINE_INTENSITY));
+        }
+        if let Some(value) = arg.strip_prefix("--scanlines=") {
+            let intensity: u8 = value.parse()
+                .map_err(|_| format!("Invalid scanline intensity: {}", value))?;
+            if intensity > 100 {
+                return Err(format!("Scanline intensity must be between 0 and 100, got {}", intensity).into());
+            }
+            return Ok(Some(intensity));
+        }
+    }
+    Ok(None)
+}
+
+// Accepts --scale N or --scale=N from 1 to MAX_SCALE, falling back to DEFAULT_SCALE
+fn get_scale(args: &[String]) -> Result<usize, Box<dyn Error>> {
+    let value = match args.iter().position(|arg| arg == "--scale") {
+        Some(i) => args.get(i + 1).ok_or("--scale needs a number of output pixels per pixel")?,
+        None => match args.iter().find_map(|arg| arg.strip_prefix("--scale=")) {
+            Some(value) => value,
+            None => return Ok(DEFAULT_SCALE),
+        },
+    };
+    match value.parse() {
+        Ok(scale) if (1..=MAX_SCALE).contains(&scale) => Ok(scale),
+        _ => Err(format!("--scale must be a whole number from 1 to {}, got {}", MAX_SCALE, value).into()),
+    }
+}
+
+// Accepts --cps N or --cps=N, falling back to CPS when the flag isn't given
+fn get_cps(args: &[String]) -> Result<usize, Box<dyn Error>> {
+    let value = match args.iter().position(|arg| arg == "--cps") {
+        Some(i) => args.get(i + 1).ok_or("--cps needs a number of instructions per second")?,
+        None => match args.iter().find_map(|arg| arg.strip_prefix("--cps=")) {
+            Some(value) => value,
+            None => return Ok(CPS),
+        },
+    };
+    match value.parse() {
+        Ok(cps) if cps > 0 => Ok(cps),
+        _ => Err(format!("--cps must be a positive integer, got {}", value).into()),
+    }
+}
+
+// --compare TRACE runs the ROM headless in lockstep with a reference trace (format in
+// core::reftrace) and prints where the two first disagree. Returns false without the flag.
+fn run_compare(args: &[String], program: &[u8], quirks: Quirks) -> Result<bool, Box<dyn Error>> {
+    let path = match args.iter().position(|arg| arg == "--compare") {
+        Some(i) => args.get(i + 1).ok_or("--compare needs a reference trace file")?,
+        None => return Ok(false),
+    };
+    let reference = reftrace::parse_trace(&fs::read_to_string(path)?)?;
+    let mut core = Core::try_with_quirks(program, quirks)?;
+    match reftrace::compare(&mut core, &reference, &[]) {
+        Ok(None) => println!("matched all {} trace lines", reference.len()),
+        Ok(Some(divergence)) => println!("{}", divergence),
+        Err(err) => println!("core error after {} instructions: {}", core.instruction_count(), err),
+    }
+    Ok(true)
+}
+
+fn write_to_buffer(core: &Core, buffer: &mut [u32], options: &RenderOptions) {
+    let (cols, rows) = core.display_dimensions();
+    let (pixel_w, pixel_h) = options.pixel_size((cols, rows));
+    // Which spots of a cell get lit, worked out once per frame. Only shaped when dots are
+    // on so the default is a solid block.
+    let cell: Vec<bool> = (0..pixel_w * pixel_h)
+        .map(|i| !options.dots || in_dot(i % pixel_w, i / pixel_w, pixel_w, pixel_h))
+        .collect();
+
+    // One output row at a time, filling a pixel_w wide run per source pixel
+    for (y, row) in buffer.chunks_exact_mut(cols * pixel_w).enumerate() {
+        let cell_row = &cell[(y % pixel_h) * pixel_w..][..pixel_w];
+        // Scanlines work on the scaled buffer, darkening every other output row
+        let scanline = options.scanlines.filter(|_| y % 2 == 1);
+        for (x, run) in row.chunks_exact_mut(pixel_w).enumerate() {
+            let original_color = core.pixel_color(x, y / pixel_h);
+            for (pixel, &in_cell) in run.iter_mut().zip(cell_row) {
+                let color = match original_color != 0 && in_cell {
+                    true => PALETTE[original_color as usize],
+                    false => PALETTE[0],
+                };
+                *pixel = match scanline {
+                    Some(intensity) => darken(color, intensity),
+                    None => color,
+                };
+            }
+        }
+    }
+}
+
+// Whether a position inside a cell_w x cell_h cell falls within its rounded dot. The dot
+// is one pixel smaller each way to leave a gap between neighbours, with corners cut on a
+// radius of a third of the dot (3 for the default 10x10 cells, smaller in hires).
+fn in_dot(cx: usize, cy: usize, cell_w: usize, cell_h: usize) -> bool {
+    // Too small to leave a gap and still show a dot, so these stay solid
+    if cell_w < 3 || cell_h < 3 {
+        return true;
+    }
+    let (dot_w, dot_h) = (cell_w - 1, cell_h - 1);
+    if cx >= dot_w || cy >= dot_h {
+        return false;
+    }
+    let radius = dot_w.min(dot_h) / 3;
+    // Distance to the nearest corner circle centre, zero along the straight edges
+    let corner_dist = |c: usize, size: usize| {
+        if c < radius {
+            radius - c
+        } else if c >= size - radius {
+            c + radius + 1 - size
+        } else {
+            0
+        }
+    };
+    let (dx, dy) = (corner_dist(cx, dot_w), corner_dist(cy, dot_h));
+    dx * dx + dy * dy <= radius * radius
+}
+
+fn darken(color: u32, intensity: u8) -> u32 {
+    let keep = 100 - intensity as u32;
+    let scale = |shift: u32| (((color >> shift) & 0xFF) * keep / 100) << shift;
+    (color & 0xFF000000) | scale(16) | scale(8) | scale(0)
+}
+
+// Outlines the last sprite draw in the output buffer, (x, y, w, h) in CHIP-8 pixels.
+// Sprites that run off the right or bottom edge are clipped to the screen.
+fn draw_rect_overlay(rect: (u8, u8, u8, u8), dims: (usize, usize), buffer: &mut [u32], options: &RenderOptions) {
+    let (x, y, w, h) = rect;
+    if w == 0 || h == 0 {
+        return;
+    }
+    let (pixel_w, pixel_h) = options.pixel_size(dims);
+    let (width, height) = options.buffer_size(dims);
+    let left = x as usize * pixel_w;
+    let top = y as usize * pixel_h;
+    // A rect from another resolution could start off the edge
+    if left >= width || top >= height {
+        return;
+    }
+    let right = ((x as usize + w as usize) * pixel_w).min(width) - 1;
+    let bottom = ((y as usize + h as usize) * pixel_h).min(height) - 1;
+
+    for col in left..=right {
+        buffer[top * width + col] = DRAW_RECT_COLOR;
+        buffer[bottom * width + col] = DRAW_RECT_COLOR;
+    }
+    for row in top..=bottom {
+        buffer[row * width + left] = DRAW_RECT_COLOR;
+        buffer[row * width + right] = DRAW_RECT_COLOR;
     }
 }
 
@@ -66,52 +491,300 @@ fn keymap(key: &Key) -> Option<u8> {
 
 
 
+// --autoswap only considers a swap once more than this share of words don't decode
+const AUTOSWAP_MIN_INVALID: f64 = 0.25;
+
+// Share of a ROM's words that don't decode to anything this build runs, going by a
+// straight disassembly like --check. A trailing odd byte isn't counted.
+fn invalid_share(program: &[u8]) -> f64 {
+    let words: Vec<u16> = disasm::disassemble_rom(program, 0x200)
+        .into_iter()
+        .filter(|(_, _, mnemonic)| !mnemonic.starts_with("DB"))
+        .map(|(_, opcode, _)| opcode)
+        .collect();
+    if words.is_empty() {
+        return 0.0;
+    }
+    let invalid = words.iter().filter(|&&opcode| OpcodeKind::of(opcode).is_none()).count();
+    invalid as f64 / words.len() as f64
+}
+
+// --autoswap: a ROM dumped with its bytes swapped decodes mostly to garbage, so when too
+// much of it is invalid it's swapped pairwise and kept that way if that at least halves
+// the invalid share. Returns the ROM to load and the message to log if it was swapped.
+fn autoswap(program: Vec<u8>) -> (Vec<u8>, Option<String>) {
+    let before = invalid_share(&program);
+    if before <= AUTOSWAP_MIN_INVALID {
+        return (program, None);
+    }
+    let mut swapped = program.clone();
+    for pair in swapped.chunks_exact_mut(2) {
+        pair.swap(0, 1);
+    }
+    let after = invalid_share(&swapped);
+    if after * 2.0 > before {
+        return (program, None);
+    }
+    let message = format!(
+        "autoswap: {:.0}% of words didn't decode, {:.0}% with bytes swapped; loading the swapped ROM",
+        before * 100.0, after * 100.0
+    );
+    (swapped, Some(message))
+}
+
+// What --warn-odd prints for a ROM that isn't a whole number of instructions
+fn odd_length_warning(program: &[u8]) -> Option<String> {
+    (!program.len().is_multiple_of(2))
+        .then(|| format!("warning: ROM is {} bytes, an odd length; it may be truncated", program.len()))
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
-    let program = get_program(&args)?;
-    let legacy_mode = args.iter().any(|arg| arg == "--legacy");
+    // Takes its ROMs from the manifest instead of the command line
+    if run_batch(&args)? {
+        return Ok(());
+    }
+    let mut program = get_program(&args)?;
+    if args.iter().any(|arg| arg == "--autoswap") {
+        let (checked, message) = autoswap(program);
+        program = checked;
+        if let Some(message) = message {
+            eprintln!("{}", message);
+        }
+    }
+    // Instructions are 2 bytes, so an odd length usually means a truncated download.
+    // The ROM still loads as-is, with its last byte followed by zeroed RAM.
+    if let Some(warning) = odd_length_warning(&program).filter(|_| args.iter().any(|arg| arg == "--warn-odd")) {
+        eprintln!("{}", warning);
+    }
+    let quirks = get_quirks(&args);
+    if run_check(&args, &program, quirks) {
+        return Ok(());
+    }
+    if run_compare(&args, &program, quirks)? {
+        return Ok(());
+    }
+    let cps = get_cps(&args)?;
+    if run_quirk_diff(&args, &program, quirks, cps)? {
+        return Ok(());
+    }
+    let render_options = RenderOptions {
+        scanlines: get_scanlines(&args)?,
+        dots: args.iter().any(|arg| arg == "--dots"),
+        aspect: args.iter().any(|arg| arg == "--aspect"),
+        scale: get_scale(&args)?,
+    };
+    let (width, height) = render_options.buffer_size((SCREEN_WIDTH, SCREEN_HEIGHT));
+    let mut frame_stats = args.iter()
+        .any(|arg| arg == "--timing-log")
+        .then(|| FrameStats::new(FPS));
+    let mut wav_recorder = get_wav_path(&args)?
+        .map(|path| WavRecorder::new(&path, FPS, AUDIO_FREQ, AUDIO_VOL));
 
-    let mut core = Core::new(&program, legacy_mode);
-    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
+    let mut core = Core::try_with_quirks(&program, quirks)?;
+    let mut buffer: Vec<u32> = vec![0; width * height];
 
+    let title = format!("Chip8 emulator ({}) - ESC to exit", core.profile_name());
     let mut window = Window::new(
-        "Chip8 emulator - ESC to exit",
-        WIDTH,
-        HEIGHT,
+        &title,
+        width,
+        height,
         WindowOptions::default(),
     )?;
 
+    // Timing model: instructions run in per-frame batches, but the timers follow the wall
+    // clock. Each frame adds the real time since the last one to an accumulator and takes
+    // out one tick per whole 60Hz period, carrying the remainder over, so the timers run
+    // at exactly 60Hz on average even when frames come late or the cps changes.
     let mut prev_time = Instant::now();
+    let mut timer_accumulator = Duration::ZERO;
 
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
     let sink = Sink::try_new(&stream_handle).unwrap();
 
-    let source = SineWave::new(AUDIO_FREQ).amplify(AUDIO_VOL);
-    sink.append(source);
+    // The beep plays continuously and the core gates it on sound timer transitions
+    let beeping = Arc::new(AtomicBool::new(false));
+    sink.append(Beep::new(beeping.clone(), AUDIO_FREQ, AUDIO_VOL));
+    core.on_sound_change(move |active| beeping.store(active, Ordering::Relaxed));
+
+    let mut invalid_ops = InvalidOpLog { hits: HashMap::new() };
+
+    // F1 toggles an outline around the most recently drawn sprite
+    let mut show_draw_rect = false;
+    // Forces a redraw for changes the core doesn't see, the first frame and the overlay
+    let mut redraw = true;
+    // Set once the core hits an error it can't carry on from. Cycling stops with the
+    // screen left as it was until F5 resets the ROM.
+    let mut stopped = false;
+    // --sleep-on-delay: a ROM spinning on FX07 can't get anywhere until the next timer
+    // tick, so those frames run no cycles and the frame limiter does the sleeping. The
+    // timers follow the wall clock either way.
+    let sleep_on_delay = args.iter().any(|arg| arg == "--sleep-on-delay");
 
     window.set_target_fps(FPS);
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        let cycles_per_frame = CPS / FPS;
-        let all_keys_pressed = window.get_keys_pressed(minifb::KeyRepeat::Yes);
+    // Runs until the window closes, Escape, or the ROM exits with 00FD
+    let mut frame = 0;
+    while window.is_open() && !window.is_key_down(Key::Escape) && !core.is_halted() {
+        // Spreads the remainder over the second when cps isn't a multiple of FPS
+        let cycles_per_frame = frame_cycles(cps, frame);
+        frame += 1;
+        if window.is_key_pressed(Key::F1, minifb::KeyRepeat::No) {
+            show_draw_rect = !show_draw_rect;
+            redraw = true;
+        }
+        if window.is_key_pressed(Key::F5, minifb::KeyRepeat::No) {
+            // Reloads the ROM too, in case it overwrote itself before failing
+            core.reset_and_reload(&program)?;
+            stopped = false;
+            window.set_title(&title);
+        }
+        // Keys currently held, FX0A needs to see them released and EX9E/EXA1 test held
+        let all_keys_pressed = window.get_keys();
         let keys: Vec<u8> = all_keys_pressed
             .iter()
-            .filter_map(|x| keymap(x))
+            .filter_map(keymap)
             .collect();
 
-        for _ in 0..cycles_per_frame {
-            let now = Instant::now();
-            if now - prev_time >= TIMER_PERIOD {
-                core.decrement_timers();
+        let now = Instant::now();
+        timer_accumulator += now - prev_time;
+        prev_time = now;
+        while timer_accumulator >= TIMER_PERIOD {
+            core.decrement_timers();
+            timer_accumulator -= TIMER_PERIOD;
+        }
 
-                prev_time = now;
+        // run_frame hands back early on a display-wait draw, a halt, an FX0A or a
+        // jump-to-self, and on an error. Invalid opcodes are skipped over and the rest of
+        // the budget still runs.
+        let mut budget = cycles_per_frame;
+        if sleep_on_delay && core.is_waiting_on_delay_timer() {
+            budget = 0;
+        }
+        while budget > 0 && !stopped {
+            let start = core.instruction_count();
+            let result = core.run_frame(budget, &keys);
+            budget -= (core.instruction_count() - start) as usize;
+            match result {
+                Ok(_) => break,
+                // Keep going past invalid ops like before, the ROM may never hit that path again
+                // pc has already moved past it
+                Err(Chip8Error::InvalidOpcode(opcode)) => {
+                    // instruction_count leaves out the failed instruction, it still took a cycle
+                    budget -= 1;
+                    if let Some(line) = invalid_ops.report(core.pc().wrapping_sub(2), opcode) {
+                        eprintln!("{}", line);
+                    }
+                }
+                Err(err) => {
+                    // pc has already moved past the failing instruction
+                    let message = format!("{} at {:#06X}", err, core.pc().wrapping_sub(2));
+                    eprintln!("stopped: {}", message);
+                    window.set_title(&format!(
+                        "Chip8 emulator ({}) - stopped: {} - F5 to reset, ESC to exit",
+                        core.profile_name(), message
+                    ));
+                    stopped = true;
+                }
             }
-            if core.sound_active() { sink.play(); } else { sink.pause(); }
-            core.cycle(&keys);
         }
-        write_to_buffer(&core.display, &mut buffer);
-        window.update_with_buffer(&buffer, WIDTH, HEIGHT)?;
+        // An unchanged screen skips the redraw, update still polls input and keeps the fps
+        if redraw || core.display_dirty() {
+            core.clear_dirty();
+            redraw = false;
+            // Hires halves the pixel size, the buffer can come out a little smaller than the
+            // window when the height doesn't divide evenly and minifb stretches it to fit
+            let dims = core.display_dimensions();
+            let (buffer_w, buffer_h) = render_options.buffer_size(dims);
+            buffer.resize(buffer_w * buffer_h, 0);
+            write_to_buffer(&core, &mut buffer, &render_options);
+            if let Some(rect) = core.last_draw_rect().filter(|_| show_draw_rect) {
+                draw_rect_overlay(rect, dims, &mut buffer, &render_options);
+            }
+            window.update_with_buffer(&buffer, buffer_w, buffer_h)?;
+        } else {
+            window.update();
+        }
+        if let Some(stats) = frame_stats.as_mut() {
+            stats.record();
+        }
+        if let Some(recorder) = wav_recorder.as_mut() {
+            recorder.record_frame(core.is_sound_active());
+        }
+    }
+
+    if let Some(mut recorder) = wav_recorder {
+        recorder.finish()?;
     }
     
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_op_log_collapses_repeats() {
+        let mut log = InvalidOpLog { hits: HashMap::new() };
+        let logged = (0..2 * INVALID_OP_SUMMARY_EVERY + 1)
+            .filter(|_| log.report(0x300, 0xFFFF).is_some())
+            .count();
+        // The first hit, then one summary per INVALID_OP_SUMMARY_EVERY repeats
+        assert_eq!(logged, 3);
+        assert_eq!(log.report(0x300, 0xFFFF), None);
+
+        // Same opcode somewhere else is a new report, as is another opcode at the same pc
+        assert!(log.report(0x302, 0xFFFF).is_some());
+        assert!(log.report(0x300, 0xFFFE).is_some());
+    }
+
+    #[test]
+    fn odd_length_warns() {
+        assert_eq!(odd_length_warning(&[0x00, 0xE0]), None);
+        let warning = odd_length_warning(&[0x00, 0xE0, 0x12]).unwrap();
+        assert!(warning.contains("3 bytes"));
+    }
+
+    #[test]
+    fn legacy_only_sets_the_shift_quirk() {
+        let args = |flags: &[&str]| flags.iter().map(|flag| flag.to_string()).collect::<Vec<_>>();
+        let legacy = get_quirks(&args(&["emu", "rom", "--legacy"]));
+        assert_eq!(legacy, Quirks { shift_uses_vy: true, ..Quirks::default() });
+        assert_eq!(get_quirks(&args(&["emu", "rom", "--vip"])), Quirks::cosmac_vip());
+        assert_eq!(get_quirks(&args(&["emu", "rom", "--quirk-shift"])), legacy);
+    }
+
+    #[test]
+    fn autoswap_undoes_swapped_dumps() {
+        // CLS four times and a load, which swapped are four E000s and a jump
+        let rom: Vec<u8> = [0x00E0u16, 0x00E0, 0x00E0, 0x00E0, 0x6012]
+            .iter()
+            .flat_map(|op| op.to_be_bytes())
+            .collect();
+        let swapped: Vec<u8> = rom.chunks(2).flat_map(|pair| [pair[1], pair[0]]).collect();
+        assert_eq!(invalid_share(&swapped), 0.8);
+
+        let (fixed, message) = autoswap(swapped);
+        assert_eq!(fixed, rom);
+        assert!(message.unwrap().contains("80%"));
+        // A ROM that decodes fine, or is garbage either way round, loads as-is
+        assert_eq!(autoswap(rom.clone()), (rom, None));
+        assert_eq!(autoswap(vec![0xE0, 0xE0, 0xE0, 0xE0]).1, None);
+    }
+
+    #[test]
+    fn dropped_wav_recorder_still_writes() {
+        let path = std::env::temp_dir().join(format!("chip8-drop-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        {
+            let mut recorder = WavRecorder::new(&path, FPS, AUDIO_FREQ, AUDIO_VOL);
+            recorder.record_frame(true);
+            recorder.record_frame(false);
+        }
+        // 44 bytes of header then two frames of 16-bit samples
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(written.len(), 44 + 2 * 2 * wav::SAMPLE_RATE as usize / FPS);
+        assert_eq!(&written[..4], b"RIFF");
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// 16-bit mono PCM at 44.1kHz, so each 60Hz frame is exactly 735 samples
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// Captures the beep as a square wave, one frame at a time, and writes it out as a WAV.
+// Dropping it without calling finish still writes the file, so the recording survives an
+// early return or a panic, it just can't report a failure to write other than on stderr.
+pub struct WavRecorder {
+    path: String,
+    samples_per_frame: usize,
+    freq: f32,
+    amplitude: i16,
+    samples: Vec<i16>,
+    // position within the current square wave period, in samples
+    phase: f32,
+    finished: bool,
+}
+
+impl WavRecorder {
+    pub fn new(path: &str, fps: usize, freq: f32, volume: f32) -> Self {
+        Self {
+            path: path.to_string(),
+            samples_per_frame: SAMPLE_RATE as usize / fps,
+            freq,
+            amplitude: (volume * i16::MAX as f32) as i16,
+            samples: Vec::new(),
+            phase: 0.0,
+            finished: false,
+        }
+    }
+
+    // Call once per presented frame with whether the sound timer was active
+    pub fn record_frame(&mut self, beeping: bool) {
+        let period = SAMPLE_RATE as f32 / self.freq;
+        for _ in 0..self.samples_per_frame {
+            let sample = if !beeping {
+                0
+            } else if self.phase < period / 2.0 {
+                self.amplitude
+            } else {
+                -self.amplitude
+            };
+            self.samples.push(sample);
+            self.phase = (self.phase + 1.0) % period;
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.write()
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        let data_len = (self.samples.len() * 2) as u32;
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&(36 + data_len).to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&1u16.to_le_bytes())?; // mono
+        out.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        out.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?; // byte rate
+        out.write_all(&2u16.to_le_bytes())?; // block align
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+
+        out.write_all(b"data")?;
+        out.write_all(&data_len.to_le_bytes())?;
+        for sample in &self.samples {
+            out.write_all(&sample.to_le_bytes())?;
+        }
+        out.flush()
+    }
+}
+
+impl Drop for WavRecorder {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(err) = self.write() {
+                eprintln!("couldn't write {}: {}", self.path, err);
+            }
+        }
+    }
+}